@@ -0,0 +1,240 @@
+//! Sockets passed in by systemd socket activation, see sd_listen_fds(3).
+//!
+//! With the `libsystemd` feature the sockets are looked up through
+//! libsystemd. Without it the `LISTEN_PID`, `LISTEN_FDS` and `LISTEN_FDNAMES`
+//! variables are read directly, so the daemon builds where libsystemd isn't
+//! available, like musl based containers and distributions without systemd.
+use std::{
+    io,
+    os::fd::{AsRawFd, BorrowedFd, RawFd},
+};
+
+/// The first file descriptor passed by the service manager.
+pub const LISTEN_FDS_START: RawFd = 3;
+
+/// A file descriptor passed by the service manager. It stays open for the
+/// lifetime of the process, so taking ownership of it is up to the caller.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenFd {
+    pub fd: RawFd,
+    /// Name given with `FileDescriptorName=` in the socket unit.
+    pub name: Option<String>,
+}
+
+/// Returns whether `fd` is a listening `AF_UNIX` stream socket.
+pub fn is_listening_unix_stream(fd: BorrowedFd<'_>) -> io::Result<bool> {
+    imp::is_listening_unix_stream(fd.as_raw_fd())
+}
+
+/// Returns the file descriptors passed by the service manager, which is
+/// empty when the process wasn't socket activated. The environment variables
+/// are unset so child processes don't pick them up, so this should be called
+/// once, before any other threads are reading the environment.
+pub fn listen_fds() -> io::Result<Vec<ListenFd>> {
+    imp::listen_fds()
+}
+
+#[cfg(feature = "libsystemd")]
+mod imp {
+    use core::ffi::{c_char, c_int, c_size_t};
+    use std::{ffi::CStr, io, os::fd::RawFd, ptr};
+
+    use super::{ListenFd, LISTEN_FDS_START};
+
+    #[link(name = "systemd")]
+    extern "C" {
+        /// Like sd_listen_fds, but also stores the names of the file
+        /// descriptors as a NULL terminated array in `names`, which has to
+        /// be freed along with every name in it. Names that weren't set
+        /// default to "unknown".
+        ///
+        /// See sd_listen_fds_with_names(3) for more information.
+        fn sd_listen_fds_with_names(
+            unset_environment: c_int,
+            names: *mut *mut *mut c_char,
+        ) -> c_int;
+
+        /// Helper call for identifying a passed file descriptor. Returns 1 if
+        /// the file descriptor is an AF_UNIX socket of the specified type
+        /// (SOCK_DGRAM, SOCK_STREAM, ...) and path, 0 otherwise. If type is 0
+        /// a socket type check will not be done. If path is NULL a socket path
+        /// check will not be done. For normal AF_UNIX sockets set length to
+        /// 0. For abstract namespace sockets set length to the length of the
+        /// socket name (including the initial 0 byte), and pass the full
+        /// socket path in path (including the initial 0 byte). The listening
+        /// flag is used the same way as in sd_is_socket(). Returns a negative
+        /// errno style error code on failure.
+        ///
+        /// See sd_is_socket_unix(3) for more information.
+        fn sd_is_socket_unix(
+            fd: c_int,
+            r#type: c_int,
+            listening: c_int,
+            path: *const c_char,
+            length: c_size_t,
+        ) -> c_int;
+    }
+
+    fn check(result: c_int) -> io::Result<c_int> {
+        if result < 0 {
+            Err(io::Error::from_raw_os_error(-result))
+        } else {
+            Ok(result)
+        }
+    }
+
+    pub fn listen_fds() -> io::Result<Vec<ListenFd>> {
+        let mut names: *mut *mut c_char = ptr::null_mut();
+        let count = check(unsafe { sd_listen_fds_with_names(1, &mut names) })?;
+
+        let mut fds = Vec::new();
+        for idx in 0..count {
+            let name = if names.is_null() {
+                None
+            } else {
+                let name = unsafe { *names.offset(idx as isize) };
+                let owned = unsafe { CStr::from_ptr(name) }
+                    .to_string_lossy()
+                    .into_owned();
+                unsafe { libc::free(name.cast()) };
+                Some(owned).filter(|name| name != "unknown")
+            };
+
+            fds.push(ListenFd {
+                fd: LISTEN_FDS_START + idx as RawFd,
+                name,
+            });
+        }
+        if !names.is_null() {
+            unsafe { libc::free(names.cast()) };
+        }
+
+        Ok(fds)
+    }
+
+    pub fn is_listening_unix_stream(fd: RawFd) -> io::Result<bool> {
+        check(unsafe { sd_is_socket_unix(fd, libc::SOCK_STREAM, 1, ptr::null(), 0) })
+            .map(|result| result == 1)
+    }
+}
+
+#[cfg(not(feature = "libsystemd"))]
+mod imp {
+    use std::{
+        env,
+        io::{self, ErrorKind},
+        mem,
+        os::fd::RawFd,
+        process,
+    };
+
+    use libc::{c_int, c_void, socklen_t};
+
+    use super::{ListenFd, LISTEN_FDS_START};
+
+    fn take_var(name: &str) -> Option<String> {
+        let value = env::var(name).ok();
+        env::remove_var(name);
+        value
+    }
+
+    fn invalid(message: String) -> io::Error {
+        io::Error::new(ErrorKind::InvalidData, message)
+    }
+
+    pub fn listen_fds() -> io::Result<Vec<ListenFd>> {
+        let pid = take_var("LISTEN_PID");
+        let count = take_var("LISTEN_FDS");
+        let names = take_var("LISTEN_FDNAMES");
+
+        let (Some(pid), Some(count)) = (pid, count) else {
+            return Ok(Vec::new());
+        };
+
+        let pid = pid
+            .parse::<u32>()
+            .map_err(|_| invalid(format!("invalid LISTEN_PID {pid:?}")))?;
+        if pid != process::id() {
+            return Ok(Vec::new());
+        }
+
+        let count = count
+            .parse::<RawFd>()
+            .ok()
+            .filter(|count| *count >= 0)
+            .ok_or_else(|| invalid(format!("invalid LISTEN_FDS {count:?}")))?;
+
+        let mut names = names
+            .as_deref()
+            .map(|names| names.split(':').map(str::to_owned).collect::<Vec<_>>())
+            .unwrap_or_default()
+            .into_iter();
+
+        (LISTEN_FDS_START..LISTEN_FDS_START + count)
+            .map(|fd| {
+                // Like sd_listen_fds, keep the sockets from leaking into
+                // child processes.
+                let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+                if flags < 0
+                    || unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } < 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+
+                Ok(ListenFd {
+                    fd,
+                    name: names
+                        .next()
+                        .filter(|name| !name.is_empty() && name != "unknown"),
+                })
+            })
+            .collect()
+    }
+
+    fn socket_option(fd: RawFd, option: c_int) -> io::Result<c_int> {
+        let mut value: c_int = 0;
+        let mut len = mem::size_of::<c_int>() as socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                (&mut value as *mut c_int).cast::<c_void>(),
+                &mut len,
+            )
+        };
+
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(value)
+        }
+    }
+
+    pub fn is_listening_unix_stream(fd: RawFd) -> io::Result<bool> {
+        let mut stat: libc::stat = unsafe { mem::zeroed() };
+        if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if stat.st_mode & libc::S_IFMT != libc::S_IFSOCK {
+            return Ok(false);
+        }
+
+        let mut address: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::sockaddr_storage>() as socklen_t;
+        if unsafe {
+            libc::getsockname(
+                fd,
+                (&mut address as *mut libc::sockaddr_storage).cast(),
+                &mut len,
+            )
+        } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(c_int::from(address.ss_family) == libc::AF_UNIX
+            && socket_option(fd, libc::SO_TYPE)? == libc::SOCK_STREAM
+            && socket_option(fd, libc::SO_ACCEPTCONN)? != 0)
+    }
+}
@@ -0,0 +1,99 @@
+//! Talks to a running daemon over its unix socket, for the command line
+//! tools.
+use std::{
+    fmt::{self, Display},
+    io,
+    path::Path,
+};
+
+use smol::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::unix::UnixStream,
+};
+
+use crate::protocol::{self, Request, Response};
+
+/// Name the command line tools introduce themselves with.
+pub const NAME: &str = "code-statistics";
+
+pub struct Client {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Json(serde_json::Error),
+    /// The daemon closed the connection before answering.
+    Closed,
+    /// The daemon answered with an error.
+    Daemon(String),
+    Unexpected(Response),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{err}"),
+            Error::Json(err) => write!(f, "invalid response: {err}"),
+            Error::Closed => write!(f, "the daemon closed the connection"),
+            Error::Daemon(message) => write!(f, "the daemon answered: {message}"),
+            Error::Unexpected(response) => write!(f, "unexpected response {response:?}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Json(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl Client {
+    /// Connects to the daemon listening at `path` and says hello, returning
+    /// the protocol version the daemon speaks.
+    pub async fn connect(path: &Path) -> Result<(Self, u32), Error> {
+        let stream = UnixStream::connect(path).await?;
+        let mut client = Client {
+            reader: BufReader::new(stream.clone()),
+            writer: stream,
+        };
+
+        let hello = Request::Hello {
+            client: NAME.to_owned(),
+            version: protocol::VERSION,
+        };
+        match client.request(&hello).await? {
+            Response::Hello { version } => Ok((client, version)),
+            response => Err(Error::Unexpected(response)),
+        }
+    }
+
+    /// Sends `request` and waits for the answer to it.
+    pub async fn request(&mut self, request: &Request) -> Result<Response, Error> {
+        let mut line = serde_json::to_string(request).map_err(Error::Json)?;
+        line.push('\n');
+        self.writer.write_all(line.as_bytes()).await?;
+
+        let mut line = String::new();
+        if self.reader.read_line(&mut line).await? == 0 {
+            return Err(Error::Closed);
+        }
+
+        match serde_json::from_str(&line).map_err(Error::Json)? {
+            Response::Error { message } => Err(Error::Daemon(message)),
+            response => Ok(response),
+        }
+    }
+}
@@ -1,11 +1,35 @@
-use std::{collections::HashSet, path::Path, time::Duration};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt::{self, Debug, Display},
+    io::{self, ErrorKind},
+    path::Path,
+    rc::Rc,
+    time::Duration,
+};
 
-use dirs::config_dir;
-use serde::{Deserialize, Deserializer};
-use smol::fs::read_to_string;
+use async_signal::{Signal, Signals};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use smol::{fs::read_to_string, stream::StreamExt};
+use tracing::{debug, info, warn};
+
+use crate::{
+    paths::{config_path, expand_home},
+    pattern::Pattern,
+    projects::Project,
+};
 
 fn deserialize_seconds<'de, D: Deserializer<'de>>(de: D) -> Result<Duration, D::Error> {
-    f64::deserialize(de).map(Duration::from_secs_f64)
+    let seconds = f64::deserialize(de)?;
+    Duration::try_from_secs_f64(seconds).map_err(|_| {
+        de::Error::custom(format!(
+            "expected a positive number of seconds, found {seconds}"
+        ))
+    })
+}
+
+fn serialize_seconds<S: Serializer>(duration: &Duration, ser: S) -> Result<S::Ok, S::Error> {
+    duration.as_secs_f64().serialize(ser)
 }
 
 fn default_timeout() -> Duration {
@@ -20,19 +44,184 @@ fn default_debounce() -> Duration {
     Duration::from_secs(5)
 }
 
-#[derive(Deserialize, Debug)]
+/// When the log file is synced to disk.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FsyncPolicy {
+    /// Leave writing back to the operating system.
+    Never,
+    /// Sync whenever a session starts or ends.
+    #[default]
+    Session,
+    /// Sync after every write, including heartbeats.
+    Always,
+}
+
+/// Gives every project matching the patterns the name `project`. Patterns
+/// that are left out match anything, but at least one has to be given.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ProjectAlias {
+    /// Matched against the name of the project.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<Pattern>,
+    /// Matched against the root path or remote URL of the project.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<Pattern>,
+    pub project: String,
+}
+
+impl ProjectAlias {
+    fn matches(&self, project: &Project) -> bool {
+        if self.name.is_none() && self.path.is_none() {
+            return false;
+        }
+
+        self.name
+            .as_ref()
+            .is_none_or(|name| name.is_match(&project.name))
+            && self.path.as_ref().is_none_or(|path| {
+                project
+                    .identity
+                    .as_ref()
+                    .is_some_and(|identity| path.is_match(identity))
+            })
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct ProjectsConfig {
+    /// Checked in order, the first alias that matches is used.
+    #[serde(default)]
+    pub aliases: Vec<ProjectAlias>,
+}
+
+impl ProjectsConfig {
+    /// Applies the first alias that matches `project`. Aliased projects lose
+    /// their identity, so that everything given the same name is counted as
+    /// one project.
+    pub fn resolve(&self, project: Project) -> Project {
+        match self.aliases.iter().find(|alias| alias.matches(&project)) {
+            Some(alias) => Project {
+                name: alias.project.clone(),
+                identity: None,
+            },
+            None => project,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct LanguagesConfig {
+    /// Maps the filetypes editors send to the language they are counted as.
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+    /// Named sets of languages that reports also show the total time of.
+    #[serde(default)]
+    pub groups: HashMap<String, Vec<String>>,
+}
+
+impl LanguagesConfig {
+    pub fn resolve<'a>(&'a self, language: &'a str) -> &'a str {
+        self.aliases
+            .get(language)
+            .map_or(language, |alias| alias.as_str())
+    }
+
+    /// Whether `language` is `filter` or belongs to the group called
+    /// `filter`.
+    pub fn matches(&self, filter: &str, language: &str) -> bool {
+        let language = self.resolve(language);
+        self.resolve(filter) == language
+            || self.groups.get(filter).is_some_and(|members| {
+                members
+                    .iter()
+                    .any(|member| self.resolve(member) == language)
+            })
+    }
+}
+
+/// Work that is never recorded. The rules are matched against what the
+/// editor sends, before any aliases are applied.
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct IgnoreConfig {
+    #[serde(default)]
+    pub languages: Vec<Pattern>,
+    /// Matched against both the name and the identity of the project.
+    #[serde(default)]
+    pub projects: Vec<Pattern>,
+    /// Projects whose root is inside one of these directories are ignored. A
+    /// leading `~` stands for the home directory.
+    #[serde(default)]
+    pub paths: Vec<String>,
+}
+
+impl IgnoreConfig {
+    /// Whether work in `language` on `project`, rooted at `root`, should be
+    /// left out.
+    pub fn ignores(&self, language: &str, project: &Project, root: Option<&str>) -> bool {
+        let root = root.or_else(|| {
+            project
+                .identity
+                .as_deref()
+                .filter(|identity| identity.starts_with('/'))
+        });
+
+        self.languages
+            .iter()
+            .any(|pattern| pattern.is_match(language))
+            || self.projects.iter().any(|pattern| {
+                pattern.is_match(&project.name)
+                    || project
+                        .identity
+                        .as_ref()
+                        .is_some_and(|identity| pattern.is_match(identity))
+            })
+            || root.is_some_and(|root| {
+                self.paths.iter().any(|path| {
+                    expand_home(path).is_some_and(|path| Path::new(root).starts_with(path))
+                })
+            })
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
 pub struct Config {
     #[serde(default)]
     pub ignored_languages: HashSet<String>,
-    #[serde(deserialize_with = "deserialize_seconds", default = "default_timeout")]
+    #[serde(
+        deserialize_with = "deserialize_seconds",
+        serialize_with = "serialize_seconds",
+        default = "default_timeout"
+    )]
     pub timeout: Duration,
     #[serde(
         deserialize_with = "deserialize_seconds",
+        serialize_with = "serialize_seconds",
         default = "default_heartbeat"
     )]
     pub heartbeat_frequency: Duration,
-    #[serde(deserialize_with = "deserialize_seconds", default = "default_debounce")]
+    #[serde(
+        deserialize_with = "deserialize_seconds",
+        serialize_with = "serialize_seconds",
+        default = "default_debounce"
+    )]
     pub debounce_amount: Duration,
+    #[serde(default)]
+    pub fsync: FsyncPolicy,
+    #[serde(default)]
+    pub projects: ProjectsConfig,
+    #[serde(default)]
+    pub languages: LanguagesConfig,
+    #[serde(default)]
+    pub ignore: IgnoreConfig,
+    /// Where the log and tag files are kept. Only read when the daemon
+    /// starts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_dir: Option<String>,
+    /// Where the daemon listens when it isn't started through a systemd
+    /// socket. Only read when the daemon starts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socket: Option<String>,
 }
 
 impl Default for Config {
@@ -42,22 +231,320 @@ impl Default for Config {
             timeout: default_timeout(),
             heartbeat_frequency: default_heartbeat(),
             debounce_amount: default_debounce(),
+            fsync: FsyncPolicy::default(),
+            projects: ProjectsConfig::default(),
+            languages: LanguagesConfig::default(),
+            ignore: IgnoreConfig::default(),
+            data_dir: None,
+            socket: None,
         }
     }
 }
 
-pub async fn read_config() -> Config {
-    let Ok(config_file) = read_to_string(
-        config_dir()
-            .expect("Failed to get config dir")
-            .join(Path::new("code-statistics/config.toml")),
-    )
-    .await
-    else {
-        return Config::default();
+impl Config {
+    /// Checks the settings that are valid on their own but not together,
+    /// returning a message for each problem.
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if self.timeout.is_zero() {
+            errors.push("timeout must be longer than 0 seconds".to_owned());
+        }
+        if self.heartbeat_frequency.is_zero() {
+            errors.push("heartbeat_frequency must be longer than 0 seconds".to_owned());
+        }
+        if self.debounce_amount >= self.timeout {
+            errors.push(format!(
+                "debounce_amount ({}s) must be shorter than timeout ({}s)",
+                self.debounce_amount.as_secs_f64(),
+                self.timeout.as_secs_f64()
+            ));
+        }
+
+        for (idx, alias) in self.projects.aliases.iter().enumerate() {
+            if alias.name.is_none() && alias.path.is_none() {
+                errors.push(format!(
+                    "projects.aliases[{idx}] needs a name or path pattern to match"
+                ));
+            }
+            if alias.project.is_empty() || alias.project.contains(char::is_control) {
+                errors.push(format!(
+                    "projects.aliases[{idx}] has an invalid project name {:?}",
+                    alias.project
+                ));
+            }
+        }
+
+        for (language, alias) in &self.languages.aliases {
+            if alias.is_empty() {
+                errors.push(format!("languages.aliases.{language} is empty"));
+            }
+        }
+
+        errors
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    /// The config parsed, but some of its settings don't make sense. Unknown
+    /// keys are kept as well, since a misspelled key is often why.
+    Invalid {
+        errors: Vec<String>,
+        warnings: Vec<String>,
+    },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{err}"),
+            Error::Parse(err) => write!(f, "{}", err.to_string().trim_end()),
+            Error::Invalid { errors, warnings } => {
+                write!(f, "{}", errors.join("\n"))?;
+                for warning in warnings {
+                    write!(f, "\nwarning: {warning}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Parse(err) => Some(err),
+            Error::Invalid { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(err: toml::de::Error) -> Self {
+        Error::Parse(err)
+    }
+}
+
+/// A config that was read successfully, with warnings about anything in it
+/// that was left unused.
+#[derive(Debug)]
+pub struct Loaded {
+    pub config: Config,
+    pub warnings: Vec<String>,
+}
+
+/// Parses and validates a config, warning about unknown keys instead of
+/// silently ignoring them.
+pub fn parse_config(contents: &str) -> Result<Loaded, Error> {
+    let mut warnings = Vec::new();
+    let config: Config = serde_ignored::deserialize(toml::Deserializer::new(contents), |path| {
+        warnings.push(format!("unknown key {path}"));
+    })?;
+
+    let errors = config.validate();
+    if !errors.is_empty() {
+        return Err(Error::Invalid { errors, warnings });
+    }
+
+    Ok(Loaded { config, warnings })
+}
+
+/// The config the daemon is running with, which is replaced as a whole when
+/// it is reloaded. Tasks should call [`SharedConfig::get`] whenever they need
+/// a setting instead of holding on to the result.
+#[derive(Clone)]
+pub struct SharedConfig(Rc<RefCell<Rc<Config>>>);
+
+impl SharedConfig {
+    pub fn new(config: Config) -> Self {
+        SharedConfig(Rc::new(RefCell::new(Rc::new(config))))
+    }
+
+    pub fn get(&self) -> Rc<Config> {
+        self.0.borrow().clone()
+    }
+
+    pub fn replace(&self, config: Config) {
+        *self.0.borrow_mut() = Rc::new(config);
+    }
+}
+
+impl Debug for SharedConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.get().fmt(f)
+    }
+}
+
+/// Reads the config, using the defaults if there is no config file.
+pub async fn load_config() -> Result<Loaded, Error> {
+    match read_to_string(config_path()).await {
+        Ok(contents) => parse_config(&contents),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(Loaded {
+            config: Config::default(),
+            warnings: Vec::new(),
+        }),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Reloads `config` whenever the daemon receives `SIGHUP`. A config that
+/// fails to parse is reported and the previous one is kept.
+pub async fn reload_on_hangup(config: SharedConfig) {
+    let mut signals = match Signals::new([Signal::Hup]) {
+        Ok(signals) => signals,
+        Err(err) => {
+            warn!(%err, "Failed to listen for SIGHUP, the config will not be reloaded");
+            return;
+        }
     };
 
-    toml::from_str(&config_file)
-        .ok()
-        .unwrap_or_else(Config::default)
+    while let Some(signal) = signals.next().await {
+        if let Err(err) = signal {
+            warn!(%err, "Failed to receive signal");
+            continue;
+        }
+
+        match load_config().await {
+            Ok(loaded) => {
+                for warning in loaded.warnings {
+                    warn!("{warning} in {}", config_path().display());
+                }
+                info!("Reloaded config");
+                debug!(config = ?loaded.config);
+                config.replace(loaded.config);
+            }
+            Err(err) => warn!(%err, "Failed to reload config, keeping the previous one"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project(name: &str, identity: Option<&str>) -> Project {
+        Project {
+            name: name.to_owned(),
+            identity: identity.map(str::to_owned),
+        }
+    }
+
+    fn alias(name: Option<&str>, path: Option<&str>) -> ProjectAlias {
+        ProjectAlias {
+            name: name.map(|name| Pattern::new(name).unwrap()),
+            path: path.map(|path| Pattern::new(path).unwrap()),
+            project: "alias".to_owned(),
+        }
+    }
+
+    fn ignore(config: &str) -> IgnoreConfig {
+        parse_config(config).unwrap().config.ignore
+    }
+
+    #[test]
+    fn ignores_languages_by_glob_or_regex() {
+        let ignore = ignore("[ignore]\nlanguages = [\"mark*\", \"re:^tex\"]\n");
+        let api = project("api", None);
+
+        assert!(ignore.ignores("markdown", &api, None));
+        assert!(ignore.ignores("texinfo", &api, None));
+        assert!(!ignore.ignores("latex", &api, None));
+        assert!(!ignore.ignores("rust", &api, None));
+    }
+
+    #[test]
+    fn ignores_projects_by_name_or_identity() {
+        let ignore = ignore("[ignore]\nprojects = [\"scratch\", \"re:github.com/me/dotfiles\"]\n");
+
+        assert!(ignore.ignores("rust", &project("scratch", None), None));
+        assert!(ignore.ignores(
+            "lua",
+            &project("config", Some("https://github.com/me/dotfiles")),
+            None
+        ));
+        assert!(!ignore.ignores("rust", &project("api", Some("/home/me/scratch")), None));
+    }
+
+    #[test]
+    fn ignores_paths_inside_directories() {
+        let ignore = ignore("[ignore]\npaths = [\"~/scratch\", \"/tmp\"]\n");
+        let home = dirs::home_dir().unwrap();
+        let inside = home.join("scratch/api");
+        let inside = inside.to_str().unwrap();
+        let next_to = home.join("scratchpad");
+        let next_to = next_to.to_str().unwrap();
+        let api = project("api", None);
+
+        assert!(ignore.ignores("rust", &api, Some(inside)));
+        assert!(ignore.ignores("rust", &api, Some("/tmp/api")));
+        assert!(!ignore.ignores("rust", &api, Some(next_to)));
+        assert!(!ignore.ignores("rust", &api, None));
+    }
+
+    #[test]
+    fn path_identities_stand_in_for_the_root() {
+        let ignore = ignore("[ignore]\npaths = [\"/tmp\"]\n");
+
+        assert!(ignore.ignores("rust", &project("api", Some("/tmp/api")), None));
+        // A root that was sent wins over the identity.
+        assert!(!ignore.ignores(
+            "rust",
+            &project("api", Some("/tmp/api")),
+            Some("/home/me/api")
+        ));
+        assert!(!ignore.ignores("rust", &project("api", Some("https://host/tmp/api")), None));
+    }
+
+    #[test]
+    fn aliases_match_every_given_pattern() {
+        let forks = project("api-fork", Some("/home/me/forks/api"));
+
+        assert!(alias(Some("api*"), None).matches(&forks));
+        assert!(alias(None, Some("/home/me/forks/*")).matches(&forks));
+        assert!(alias(Some("re:^api"), Some("re:forks")).matches(&forks));
+        assert!(!alias(Some("api*"), Some("/srv/**")).matches(&forks));
+        // Globs match the whole name, regular expressions anywhere in it.
+        assert!(!alias(Some("fork"), None).matches(&forks));
+        assert!(alias(Some("re:fork"), None).matches(&forks));
+    }
+
+    #[test]
+    fn path_aliases_need_an_identity() {
+        assert!(!alias(None, Some("**")).matches(&project("api", None)));
+    }
+
+    #[test]
+    fn aliases_without_patterns_match_nothing() {
+        assert!(!alias(None, None).matches(&project("api", Some("/home/me/api"))));
+        assert!(matches!(
+            parse_config("[[projects.aliases]]\nproject = \"api\"\n"),
+            Err(Error::Invalid { .. })
+        ));
+    }
+
+    #[test]
+    fn resolving_drops_the_identity() {
+        let config = ProjectsConfig {
+            aliases: vec![alias(Some("api*"), None)],
+        };
+
+        assert_eq!(
+            config.resolve(project("api-fork", Some("/home/me/forks/api"))),
+            project("alias", None)
+        );
+        assert_eq!(
+            config.resolve(project("web", Some("/home/me/web"))),
+            project("web", Some("/home/me/web"))
+        );
+    }
 }
@@ -0,0 +1,368 @@
+use std::{collections::HashSet, future::poll_fn, io::ErrorKind, rc::Rc, sync::Arc, task::Poll};
+
+use chrono::{Local, Utc};
+use futures_concurrency::future::Race;
+use parking_lot::Mutex;
+use smol::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::unix::UnixStream,
+    Timer,
+};
+use tracing::{debug, info, trace, warn};
+
+use crate::{
+    config::{Config, SharedConfig},
+    debounce::LogMessage,
+    projects::{Project, Projects},
+    protocol::{self, Line, Period, Request, Response},
+    reader::read_sessions,
+    report::{Range, Report},
+    service::CurrentStatus,
+    tags::Tags,
+    Sender,
+};
+
+/// State shared by every client connection.
+pub struct Shared {
+    pub log: Sender<LogMessage, 5>,
+    pub languages: Rc<Tags>,
+    pub projects: Rc<Projects>,
+    pub files: Rc<Tags>,
+    pub branches: Rc<Tags>,
+    pub config: SharedConfig,
+    pub status: Arc<Mutex<CurrentStatus>>,
+}
+
+fn status(shared: &Shared) -> Response {
+    let status = shared.status.lock();
+
+    Response::Status {
+        active: status.active,
+        language: status.active.then(|| status.language.clone()),
+        project: status.active.then(|| status.project.clone()),
+        branch: status.branch.clone(),
+        since: status.since.map(|since| since.timestamp()),
+    }
+}
+
+/// The display names of the projects a `total` query asks for, or `None` to
+/// count every project. With a root or remote the project is found by its
+/// identity like when it is recorded, since its display name changes when
+/// another project shares its name. Otherwise it is found by name.
+fn queried_projects(
+    config: &Config,
+    projects: &Projects,
+    name: Option<String>,
+    root: Option<String>,
+    remote: Option<String>,
+) -> Option<HashSet<String>> {
+    if name.is_none() && root.is_none() && remote.is_none() {
+        return None;
+    }
+
+    let query = config.projects.resolve(Project::new(
+        name.unwrap_or_default(),
+        root.as_deref(),
+        remote.as_deref(),
+    ));
+
+    let names = projects
+        .all()
+        .into_iter()
+        .zip(projects.names())
+        .filter(|(project, shown)| match &query.identity {
+            Some(_) => project.identity == query.identity,
+            None => project.name == query.name || *shown == query.name,
+        })
+        .map(|(_, shown)| shown)
+        .collect();
+
+    Some(names)
+}
+
+async fn total(
+    config: &Config,
+    projects: Option<HashSet<String>>,
+    language: Option<String>,
+    period: Period,
+) -> Response {
+    let sessions = match read_sessions().await {
+        Ok(sessions) => sessions,
+        Err(err) => {
+            return Response::Error {
+                message: format!("Failed to read log file: {err}"),
+            }
+        }
+    };
+
+    let sessions = sessions.into_iter().filter(|session| {
+        projects
+            .as_ref()
+            .is_none_or(|projects| projects.contains(&session.project))
+            && language
+                .as_ref()
+                .is_none_or(|language| config.languages.matches(language, &session.language))
+    });
+
+    let (start, end) = Range::from(period).bounds(Local::now());
+    Response::Total {
+        seconds: Report::new(sessions, start, end).total.num_seconds(),
+    }
+}
+
+async fn respond(stream: &mut UnixStream, response: &Response) -> std::io::Result<()> {
+    let mut line = serde_json::to_string(response).expect("Failed to serialize response");
+    line.push('\n');
+    stream.write_all(line.as_bytes()).await
+}
+
+/// Reads messages from a client until it disconnects, forwarding them to the
+/// log task.
+pub async fn handle(stream: UnixStream, id: u128, shared: Rc<Shared>) {
+    let mut writer = stream.clone();
+    let mut buffered_stream = BufReader::new(stream);
+
+    let mut active = false;
+    let mut client = None;
+    let mut last_start = None;
+
+    info!("joined");
+
+    loop {
+        let Ok(line): Result<String, std::io::Error> = (
+            async {
+                let mut line = String::new();
+                let amount_read = buffered_stream.read_line(&mut line).await?;
+                trace!("sent data");
+                if amount_read == 0 {
+                    Err(std::io::Error::new(
+                        ErrorKind::NotConnected,
+                        "Client no longer connected",
+                    ))
+                } else {
+                    Ok(line)
+                }
+            },
+            async {
+                if active {
+                    poll_fn(|_| Poll::<()>::Pending).await;
+                } else {
+                    Timer::after(shared.config.get().timeout).await;
+                }
+                trace!("timeout reached");
+                Ok("".to_string())
+            },
+        )
+            .race()
+            .await
+        else {
+            debug!("disconnected");
+            break;
+        };
+
+        let request = match protocol::parse(&line) {
+            Ok(Line::Structured(Request::Hello {
+                client: name,
+                version,
+            })) => {
+                if version == 0 || version > protocol::VERSION {
+                    warn!(name, version, "unsupported protocol version");
+                    let message = format!(
+                        "Unsupported protocol version {version}, the newest supported version is {}",
+                        protocol::VERSION
+                    );
+                    let _ = respond(&mut writer, &Response::Error { message }).await;
+                    break;
+                }
+
+                info!(name, version, "received hello");
+                client = Some(name);
+
+                let response = Response::Hello {
+                    version: protocol::VERSION,
+                };
+                if respond(&mut writer, &response).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+            Ok(Line::Structured(_)) if client.is_none() => {
+                warn!("received message before hello");
+                let response = Response::Error {
+                    message: "Expected a hello message first".to_owned(),
+                };
+                if respond(&mut writer, &response).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+            Ok(Line::Structured(request) | Line::Legacy(request)) => request,
+            Err(err) => {
+                warn!(%err, "received invalid message");
+                if client.is_some() {
+                    let response = Response::Error {
+                        message: err.to_string(),
+                    };
+                    if respond(&mut writer, &response).await.is_err() {
+                        break;
+                    }
+                }
+                continue;
+            }
+        };
+
+        match request {
+            Request::Hello { .. } => unreachable!("Hello is handled above"),
+            Request::Status => {
+                if respond(&mut writer, &status(&shared)).await.is_err() {
+                    break;
+                }
+            }
+            Request::Total {
+                project,
+                root,
+                remote,
+                language,
+                period,
+            } => {
+                debug!(
+                    event = "received total query",
+                    project,
+                    root,
+                    remote,
+                    language,
+                    ?period
+                );
+
+                let config = shared.config.get();
+                let projects = queried_projects(&config, &shared.projects, project, root, remote);
+                let response = total(&config, projects, language, period).await;
+                if respond(&mut writer, &response).await.is_err() {
+                    break;
+                }
+            }
+            Request::End => {
+                debug!("received end message");
+                shared.log.send(LogMessage::End {
+                    id,
+                    time: Utc::now(),
+                });
+                active = false;
+                last_start = None;
+            }
+            Request::Heartbeat => {
+                let Some((language, project, file, branch)) = last_start else {
+                    trace!("ignoring heartbeat without a session");
+                    continue;
+                };
+
+                active = true;
+
+                shared.log.send(LogMessage::Start {
+                    id,
+                    time: Utc::now(),
+                    language,
+                    project,
+                    file,
+                    branch,
+                });
+            }
+            Request::Start {
+                language,
+                project,
+                file,
+                root,
+                remote,
+                branch,
+            } => {
+                debug!(
+                    event = "received start",
+                    language, project, file, root, remote, branch
+                );
+
+                let config = shared.config.get();
+                let project = Project::new(project, root.as_deref(), remote.as_deref());
+                // Like the ignore rules, ignored languages match what the
+                // editor sends, before aliases.
+                let ignored = config.ignored_languages.contains(&language)
+                    || config.ignore.ignores(&language, &project, root.as_deref());
+                let language = config.languages.resolve(&language).to_owned();
+
+                if ignored {
+                    trace!("ending session for ignored work");
+                    // Otherwise heartbeats would keep counting the time
+                    // towards whatever was being worked on before.
+                    if last_start.take().is_some() {
+                        shared.log.send(LogMessage::End {
+                            id,
+                            time: Utc::now(),
+                        });
+                    }
+                    active = false;
+                    continue;
+                }
+
+                if language.is_empty() {
+                    trace!("ignoring empty language");
+                    continue;
+                }
+
+                let project = config.projects.resolve(project);
+                if project.name.contains(char::is_control)
+                    || project
+                        .identity
+                        .as_ref()
+                        .is_some_and(|identity| identity.contains(char::is_control))
+                {
+                    warn!(?project, "ignoring project with control characters");
+                    continue;
+                }
+                if language.contains(char::is_control) {
+                    warn!(language, "ignoring language with control characters");
+                    continue;
+                }
+                if file
+                    .as_ref()
+                    .is_some_and(|file| file.contains(char::is_control))
+                {
+                    warn!(file, "ignoring file with control characters");
+                    continue;
+                }
+                if branch
+                    .as_ref()
+                    .is_some_and(|branch| branch.contains(char::is_control))
+                {
+                    warn!(branch, "ignoring branch with control characters");
+                    continue;
+                }
+                let language = shared.languages.get(&language).await;
+                let project = shared.projects.get(&project).await;
+                let file = match file {
+                    Some(file) if !file.is_empty() => Some(shared.files.get(&file).await),
+                    _ => None,
+                };
+                let branch = match branch {
+                    Some(branch) if !branch.is_empty() => Some(shared.branches.get(&branch).await),
+                    _ => None,
+                };
+
+                active = true;
+                last_start = Some((language, project, file, branch));
+
+                shared.log.send(LogMessage::Start {
+                    id,
+                    time: Utc::now(),
+                    language,
+                    project,
+                    file,
+                    branch,
+                });
+            }
+        }
+    }
+
+    shared.log.send(LogMessage::End {
+        id,
+        time: Utc::now(),
+    });
+}
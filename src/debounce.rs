@@ -1,11 +1,9 @@
-use std::time::Duration;
-
 use chrono::{DateTime, Utc};
 use futures_concurrency::future::Race;
 use smol::{stream::StreamExt, LocalExecutor, Timer};
 use tracing::{debug, span, trace, Instrument, Level};
 
-use crate::log::Status;
+use crate::{config::SharedConfig, log::Status};
 
 #[derive(Debug)]
 pub enum LogMessage {
@@ -14,6 +12,8 @@ pub enum LogMessage {
         time: DateTime<Utc>,
         language: usize,
         project: usize,
+        file: Option<usize>,
+        branch: Option<usize>,
     },
     End {
         id: u128,
@@ -23,7 +23,7 @@ pub enum LogMessage {
 }
 
 pub fn debounce<const S: usize>(
-    time: Duration,
+    config: SharedConfig,
     executor: &LocalExecutor<'_>,
 ) -> (crate::Sender<LogMessage, S>, crate::Receiver<Status, S>) {
     let (input_sender, mut input_receiver) = crate::channel::<_, S>();
@@ -43,7 +43,7 @@ pub fn debounce<const S: usize>(
                     loop {
                         let new_value = (
                             async {
-                                Timer::after(time).await;
+                                Timer::after(config.get().debounce_amount).await;
                                 trace!(event = "no activity, sending status", ?value);
                                 None
                             },
@@ -86,10 +86,12 @@ pub fn debounce<const S: usize>(
                         time,
                         language,
                         project,
+                        file,
+                        branch,
                     } => {
-                        if !matches!(last_message, Some(LogMessage::Start { id: _, time: _, language: old_language, project: old_project }) if old_language == language && old_project == project) {
-                            inter_sender.send(Status::Active { time, language, project });
-                            debug!(event = "new status", status = ?Status::Active { time, language, project });
+                        if !matches!(last_message, Some(LogMessage::Start { id: _, time: _, language: old_language, project: old_project, file: old_file, branch: old_branch }) if old_language == language && old_project == project && old_file == file && old_branch == branch) {
+                            inter_sender.send(Status::Active { time, language, project, file, branch });
+                            debug!(event = "new status", status = ?Status::Active { time, language, project, file, branch });
                         } else {
                             debug!("ignoring same status");
                         }
@@ -97,7 +99,7 @@ pub fn debounce<const S: usize>(
                         last_message = Some(value);
 
                     },
-                    LogMessage::End { id, time } => if matches!(last_message, Some(LogMessage::Start { id: old_id, time: _, language: _, project: _ }) if old_id == id) {
+                    LogMessage::End { id, time } => if matches!(last_message, Some(LogMessage::Start { id: old_id, time: _, language: _, project: _, file: _, branch: _ }) if old_id == id) {
                         last_message = Some(value);
                         inter_sender.send(Status::Dormant { time });
                         debug!(event = "new status", status = ?Status::Dormant { time });
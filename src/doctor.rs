@@ -0,0 +1,293 @@
+//! Looks for the problems that stop the daemon from recording, for
+//! `code-statistics doctor`.
+use std::{
+    fmt::{self, Display},
+    fs::{self, File},
+    io::{self, ErrorKind},
+};
+
+use zbus::Connection;
+
+use crate::{
+    client::{self, Client},
+    config::load_config,
+    format::{self, Header},
+    log::log_path,
+    manager::ManagerProxy,
+    paths::{self, config_path, data_dir, find_socket_path},
+    projects::Projects,
+    reader::{self, Reader},
+    tags::Tags,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Ok,
+    Warning,
+    Error,
+}
+
+/// The outcome of one check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub level: Level,
+    pub message: String,
+}
+
+impl Finding {
+    fn ok(message: impl Into<String>) -> Self {
+        Finding {
+            level: Level::Ok,
+            message: message.into(),
+        }
+    }
+
+    fn warning(message: impl Into<String>) -> Self {
+        Finding {
+            level: Level::Warning,
+            message: message.into(),
+        }
+    }
+
+    fn error(message: impl Into<String>) -> Self {
+        Finding {
+            level: Level::Error,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let level = match self.level {
+            Level::Ok => "ok",
+            Level::Warning => "warning",
+            Level::Error => "error",
+        };
+        write!(f, "{level:>7}: {}", self.message)
+    }
+}
+
+/// Checks the config first, since it can move the data directory and the
+/// socket.
+async fn check_config(findings: &mut Vec<Finding>) {
+    let path = config_path();
+
+    match load_config().await {
+        Ok(loaded) => {
+            findings.push(if path.exists() {
+                Finding::ok(format!("config at {} is valid", path.display()))
+            } else {
+                Finding::ok(format!(
+                    "there is no config at {}, so the defaults are used",
+                    path.display()
+                ))
+            });
+            for warning in loaded.warnings {
+                findings.push(Finding::warning(format!("{warning} in {}", path.display())));
+            }
+            paths::set_configured(&loaded.config);
+        }
+        Err(err) => findings.push(Finding::error(format!(
+            "invalid config at {}:\n{err}",
+            path.display()
+        ))),
+    }
+}
+
+/// Returns whether the data directory exists.
+fn check_data_dir(findings: &mut Vec<Finding>) -> bool {
+    let dir = data_dir();
+
+    match fs::metadata(&dir) {
+        Ok(metadata) if metadata.is_dir() => {}
+        Ok(_) => {
+            findings.push(Finding::error(format!(
+                "{} is not a directory",
+                dir.display()
+            )));
+            return false;
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            findings.push(Finding::warning(format!(
+                "{} doesn't exist yet, the daemon creates it when it starts",
+                dir.display()
+            )));
+            return false;
+        }
+        Err(err) => {
+            findings.push(Finding::error(format!(
+                "failed to read {}: {err}",
+                dir.display()
+            )));
+            return false;
+        }
+    }
+
+    let probe = dir.join(".doctor");
+    let writable = File::create(&probe).and_then(|_| fs::remove_file(&probe));
+    findings.push(match writable {
+        Ok(()) => Finding::ok(format!("{} is writable", dir.display())),
+        Err(err) => Finding::error(format!("{} is not writable: {err}", dir.display())),
+    });
+
+    true
+}
+
+async fn check_log(findings: &mut Vec<Finding>) {
+    let path = log_path();
+
+    let header = match File::open(&path)
+        .map_err(Into::into)
+        .and_then(|mut file| Header::read(&mut file))
+    {
+        Ok(header) => header,
+        Err(format::Error::Io(err)) if err.kind() == ErrorKind::NotFound => {
+            findings.push(Finding::ok("nothing has been recorded yet"));
+            return;
+        }
+        Err(err) => {
+            findings.push(Finding::error(format!(
+                "failed to read {}: {err}",
+                path.display()
+            )));
+            return;
+        }
+    };
+
+    if header.version != format::VERSION {
+        findings.push(Finding::warning(format!(
+            "{} is at version {} and is upgraded to version {} when the daemon starts, or by running code-statistics migrate",
+            path.display(),
+            header.version,
+            format::VERSION
+        )));
+        return;
+    }
+
+    // Only reading, so a check doesn't create the tag files.
+    let tags = async {
+        Ok::<_, io::Error>((
+            Tags::load("languages").await?,
+            Projects::load().await?,
+            Tags::load("files").await?,
+            Tags::load("branches").await?,
+        ))
+    };
+    let (languages, projects, files, branches) = match tags.await {
+        Ok(tags) => tags,
+        Err(err) => {
+            findings.push(Finding::error(format!(
+                "failed to read the tag files in {}: {err}",
+                data_dir().display()
+            )));
+            return;
+        }
+    };
+
+    let reader = match Reader::open(&path, &languages, &projects, &files, &branches) {
+        Ok(reader) => reader,
+        Err(err) => {
+            findings.push(Finding::error(format!(
+                "failed to read {}: {err}",
+                path.display()
+            )));
+            return;
+        }
+    };
+
+    let mut sessions = 0;
+    for session in reader {
+        match session {
+            Ok(_) => sessions += 1,
+            Err(reader::Error::Format(format::Error::Truncated)) => {
+                findings.push(Finding::warning(format!(
+                    "{} ends in an incomplete record, which is removed when the daemon starts",
+                    path.display()
+                )));
+                return;
+            }
+            Err(err) => {
+                findings.push(Finding::error(format!(
+                    "{} is damaged after {sessions} sessions: {err}",
+                    path.display()
+                )));
+                return;
+            }
+        }
+    }
+
+    findings.push(Finding::ok(format!(
+        "{} holds {sessions} sessions",
+        path.display()
+    )));
+}
+
+async fn check_socket(findings: &mut Vec<Finding>) {
+    let Some(path) = find_socket_path() else {
+        findings.push(Finding::error(
+            "XDG_RUNTIME_DIR is not set, the socket path has to be given",
+        ));
+        return;
+    };
+
+    findings.push(match Client::connect(&path).await {
+        Ok((_, version)) => Finding::ok(format!(
+            "the daemon is listening at {} and speaks protocol version {version}",
+            path.display()
+        )),
+        Err(client::Error::Io(err))
+            if matches!(
+                err.kind(),
+                ErrorKind::NotFound | ErrorKind::ConnectionRefused
+            ) =>
+        {
+            Finding::warning(format!("the daemon isn't listening at {}", path.display()))
+        }
+        Err(err) => Finding::error(format!(
+            "failed to talk to the daemon at {}: {err}",
+            path.display()
+        )),
+    });
+}
+
+async fn check_buses(findings: &mut Vec<Finding>) {
+    match Connection::system().await {
+        Ok(connection) => {
+            let can_suspend = match ManagerProxy::new(&connection).await {
+                Ok(proxy) => proxy.can_suspend().await,
+                Err(err) => Err(err),
+            };
+            findings.push(match can_suspend {
+                Ok(_) => Finding::ok("logind is available, so suspends end the session"),
+                Err(err) => Finding::warning(format!(
+                    "failed to reach logind, suspends are guessed from jumps of the clock: {err}"
+                )),
+            });
+        }
+        Err(err) => findings.push(Finding::warning(format!(
+            "failed to connect to the system bus, suspends are guessed from jumps of the clock: {err}"
+        ))),
+    }
+
+    findings.push(match Connection::session().await {
+        Ok(_) => Finding::ok("the session bus is available"),
+        Err(err) => Finding::warning(format!(
+            "failed to connect to the session bus, the D-Bus interface won't be published: {err}"
+        )),
+    });
+}
+
+/// Runs every check, in the order the daemon needs things when it starts.
+pub async fn diagnose() -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    check_config(&mut findings).await;
+    if check_data_dir(&mut findings) {
+        check_log(&mut findings).await;
+    }
+    check_socket(&mut findings).await;
+    check_buses(&mut findings).await;
+
+    findings
+}
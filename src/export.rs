@@ -0,0 +1,102 @@
+use std::{
+    io::{self, Write},
+    str::FromStr,
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+
+use crate::reader::Session;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    Jsonl,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "csv" => Ok(Format::Csv),
+            "jsonl" => Ok(Format::Jsonl),
+            _ => Err(format!(
+                "Unknown export format {format}, expected csv or jsonl"
+            )),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Row<'a> {
+    start: String,
+    end: String,
+    duration: i64,
+    language: &'a str,
+    project: &'a str,
+    file: Option<&'a str>,
+    branch: Option<&'a str>,
+}
+
+impl<'a> Row<'a> {
+    fn new(session: &'a Session) -> Self {
+        Row {
+            start: iso8601(session.start),
+            end: iso8601(session.end),
+            duration: session.duration().num_seconds(),
+            language: &session.language,
+            project: &session.project,
+            file: session.file.as_deref(),
+            branch: session.branch.as_deref(),
+        }
+    }
+}
+
+fn iso8601(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Quotes a CSV field if it contains anything that would otherwise break the
+/// row apart.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// Writes one row per session, with the duration in seconds.
+pub fn export(
+    sessions: impl IntoIterator<Item = Session>,
+    format: Format,
+    mut out: impl Write,
+) -> io::Result<()> {
+    if format == Format::Csv {
+        writeln!(out, "start,end,duration,language,project,file,branch")?;
+    }
+
+    for session in sessions {
+        let row = Row::new(&session);
+        match format {
+            Format::Csv => writeln!(
+                out,
+                "{},{},{},{},{},{},{}",
+                row.start,
+                row.end,
+                row.duration,
+                csv_field(row.language),
+                csv_field(row.project),
+                csv_field(row.file.unwrap_or_default()),
+                csv_field(row.branch.unwrap_or_default())
+            )?,
+            Format::Jsonl => {
+                serde_json::to_writer(&mut out, &row)?;
+                writeln!(out)?;
+            }
+        }
+    }
+
+    out.flush()
+}
@@ -0,0 +1,829 @@
+use std::{
+    fmt::{self, Display},
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use tracing::{info, warn};
+
+/// Bytes every log file with a header starts with.
+pub const MAGIC: [u8; 8] = *b"CODESTAT";
+
+/// The newest version of the log format, which new files are written in.
+///
+/// Version 1 stores the language index in a byte and the project index in a
+/// `u16`. Version 2 stores both as LEB128 varints, so there is no limit on the
+/// number of languages or projects. Version 3 adds the file index as a varint
+/// after the project, offset by one so that 0 means no file, and version 4
+/// adds the branch index after it in the same way.
+pub const VERSION: u8 = 4;
+
+/// Length of the header in bytes.
+///
+/// The header is laid out as the magic bytes, the format version, the byte
+/// order (0 for little endian and 1 for big endian), the widths in bytes of
+/// the language index, project index and timestamp (0 for varints), and three
+/// reserved zero bytes.
+pub const HEADER_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteOrder {
+    Little,
+    Big,
+}
+
+impl ByteOrder {
+    #[cfg(target_endian = "little")]
+    pub const NATIVE: ByteOrder = ByteOrder::Little;
+    #[cfg(target_endian = "big")]
+    pub const NATIVE: ByteOrder = ByteOrder::Big;
+
+    fn decode_u16(self, bytes: [u8; 2]) -> u16 {
+        match self {
+            ByteOrder::Little => u16::from_le_bytes(bytes),
+            ByteOrder::Big => u16::from_be_bytes(bytes),
+        }
+    }
+
+    fn encode_i64(self, value: i64) -> [u8; 8] {
+        match self {
+            ByteOrder::Little => value.to_le_bytes(),
+            ByteOrder::Big => value.to_be_bytes(),
+        }
+    }
+
+    fn decode_i64(self, bytes: [u8; 8]) -> i64 {
+        match self {
+            ByteOrder::Little => i64::from_le_bytes(bytes),
+            ByteOrder::Big => i64::from_be_bytes(bytes),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /// Format version, 0 for files written before the header existed.
+    pub version: u8,
+    pub byte_order: ByteOrder,
+    pub language_width: u8,
+    pub project_width: u8,
+    pub timestamp_width: u8,
+}
+
+impl Header {
+    /// The header new log files are created with.
+    pub const CURRENT: Header = Header {
+        version: VERSION,
+        byte_order: ByteOrder::Little,
+        language_width: 0,
+        project_width: 0,
+        timestamp_width: 8,
+    };
+
+    /// Describes files written before the header was introduced, which have
+    /// the same layout as version 1 in the byte order of the machine that
+    /// wrote them.
+    pub const LEGACY: Header = Header {
+        version: 0,
+        byte_order: ByteOrder::NATIVE,
+        language_width: 1,
+        project_width: 2,
+        timestamp_width: 8,
+    };
+
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[..MAGIC.len()].copy_from_slice(&MAGIC);
+        bytes[8] = self.version;
+        bytes[9] = match self.byte_order {
+            ByteOrder::Little => 0,
+            ByteOrder::Big => 1,
+        };
+        bytes[10] = self.language_width;
+        bytes[11] = self.project_width;
+        bytes[12] = self.timestamp_width;
+        bytes
+    }
+
+    /// Parses a header, returning `Ok(None)` if `bytes` does not start with
+    /// [`MAGIC`].
+    pub fn decode(bytes: &[u8; HEADER_LEN]) -> Result<Option<Header>, Error> {
+        if bytes[..MAGIC.len()] != MAGIC {
+            return Ok(None);
+        }
+
+        let header = Header {
+            version: bytes[8],
+            byte_order: match bytes[9] {
+                0 => ByteOrder::Little,
+                1 => ByteOrder::Big,
+                byte_order => return Err(Error::InvalidByteOrder(byte_order)),
+            },
+            language_width: bytes[10],
+            project_width: bytes[11],
+            timestamp_width: bytes[12],
+        };
+
+        if header.version == 0 || header.version > VERSION {
+            return Err(Error::UnsupportedVersion(header.version));
+        }
+
+        let layout = (
+            header.language_width,
+            header.project_width,
+            header.timestamp_width,
+        );
+        let expected = match header.version {
+            1 => (1, 2, 8),
+            _ => (0, 0, 8),
+        };
+        if layout != expected {
+            return Err(Error::UnsupportedLayout(header));
+        }
+
+        Ok(Some(header))
+    }
+
+    /// Reads the header at the start of `reader`, leaving it positioned at
+    /// the first record. Files without a header are treated as
+    /// [`Header::LEGACY`] and are read from the beginning.
+    pub fn read(reader: &mut (impl Read + Seek)) -> Result<Header, Error> {
+        let mut bytes = [0; HEADER_LEN];
+        let mut read = 0;
+        while read < HEADER_LEN {
+            match reader.read(&mut bytes[read..]) {
+                Ok(0) => break,
+                Ok(amount) => read += amount,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        if read == HEADER_LEN {
+            if let Some(header) = Header::decode(&bytes)? {
+                return Ok(header);
+            }
+        }
+
+        reader.seek(SeekFrom::Start(0))?;
+        Ok(Header::LEGACY)
+    }
+}
+
+/// A single entry of the log.
+///
+/// Starting to work on something appends a start record followed by an end
+/// record holding the same time, and the end record is then overwritten in
+/// place as time passes. A start record that is directly followed by another
+/// start record ends when the next one begins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Record {
+    Start {
+        language: usize,
+        project: usize,
+        file: Option<usize>,
+        branch: Option<usize>,
+        time: i64,
+    },
+    End {
+        time: i64,
+    },
+}
+
+impl Record {
+    /// Size of an encoded end record, which is always the last thing in the
+    /// file while a session is open.
+    pub const END_LEN: usize = 1 + size_of::<i64>();
+
+    /// Encodes the record in the layout of the current [`VERSION`].
+    pub fn encode(&self, byte_order: ByteOrder) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Record::END_LEN + 4 * MAX_VARINT_LEN);
+        match *self {
+            Record::Start {
+                language,
+                project,
+                file,
+                branch,
+                time,
+            } => {
+                bytes.push(1);
+                encode_varint(&mut bytes, language as u64);
+                encode_varint(&mut bytes, project as u64);
+                encode_varint(&mut bytes, file.map_or(0, |file| file as u64 + 1));
+                encode_varint(&mut bytes, branch.map_or(0, |branch| branch as u64 + 1));
+                bytes.extend_from_slice(&byte_order.encode_i64(time));
+            }
+            Record::End { time } => {
+                bytes.push(0);
+                bytes.extend_from_slice(&byte_order.encode_i64(time));
+            }
+        }
+        bytes
+    }
+
+    /// Reads the next record, returning `Ok(None)` at the end of the file.
+    pub fn read(reader: &mut impl Read, header: &Header) -> Result<Option<Record>, Error> {
+        let mut marker = [0];
+        if !read_exact_or_eof(reader, &mut marker)? {
+            return Ok(None);
+        }
+
+        if marker[0] == 0 {
+            return Ok(Some(Record::End {
+                time: read_i64(reader, header)?,
+            }));
+        }
+
+        let (language, project) = if header.version < 2 {
+            let mut project = [0; size_of::<u16>()];
+            read_exact(reader, &mut project)?;
+            (
+                marker[0] as usize - 1,
+                header.byte_order.decode_u16(project) as usize,
+            )
+        } else if marker[0] == 1 {
+            (read_varint(reader)?, read_varint(reader)?)
+        } else {
+            return Err(Error::InvalidRecord(marker[0]));
+        };
+
+        let file = if header.version < 3 {
+            None
+        } else {
+            read_varint(reader)?.checked_sub(1)
+        };
+
+        let branch = if header.version < 4 {
+            None
+        } else {
+            read_varint(reader)?.checked_sub(1)
+        };
+
+        Ok(Some(Record::Start {
+            language,
+            project,
+            file,
+            branch,
+            time: read_i64(reader, header)?,
+        }))
+    }
+}
+
+/// The most bytes a LEB128 encoded `u64` can take up.
+const MAX_VARINT_LEN: usize = 10;
+
+fn encode_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_varint(reader: &mut impl Read) -> Result<usize, Error> {
+    let mut value = 0u64;
+    for idx in 0..MAX_VARINT_LEN {
+        let mut byte = [0];
+        read_exact(reader, &mut byte)?;
+        // The last byte only has room for the top bit of a u64.
+        if idx == MAX_VARINT_LEN - 1 && byte[0] > 1 {
+            return Err(Error::InvalidVarint);
+        }
+        value |= u64::from(byte[0] & 0x7f) << (7 * idx);
+        if byte[0] & 0x80 == 0 {
+            return value.try_into().map_err(|_| Error::InvalidVarint);
+        }
+    }
+    Err(Error::InvalidVarint)
+}
+
+fn read_i64(reader: &mut impl Read, header: &Header) -> Result<i64, Error> {
+    let mut bytes = [0; size_of::<i64>()];
+    read_exact(reader, &mut bytes)?;
+    Ok(header.byte_order.decode_i64(bytes))
+}
+
+/// Fills `buf`, returning `false` if the reader ended before anything was
+/// read.
+fn read_exact_or_eof(reader: &mut impl Read, buf: &mut [u8]) -> Result<bool, Error> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) if read == 0 => return Ok(false),
+            Ok(0) => return Err(Error::Truncated),
+            Ok(amount) => read += amount,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(true)
+}
+
+fn read_exact(reader: &mut impl Read, buf: &mut [u8]) -> Result<(), Error> {
+    if read_exact_or_eof(reader, buf)? {
+        Ok(())
+    } else {
+        Err(Error::Truncated)
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The file ended in the middle of a record.
+    Truncated,
+    InvalidRecord(u8),
+    InvalidVarint,
+    UnsupportedVersion(u8),
+    InvalidByteOrder(u8),
+    UnsupportedLayout(Header),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{err}"),
+            Error::Truncated => write!(f, "log file ends in the middle of a record"),
+            Error::InvalidRecord(marker) => write!(f, "unknown record type {marker}"),
+            Error::InvalidVarint => write!(f, "invalid varint in record"),
+            Error::UnsupportedVersion(version) => write!(
+                f,
+                "log format version {version} is not supported, the newest supported version is {VERSION}"
+            ),
+            Error::InvalidByteOrder(byte_order) => {
+                write!(f, "invalid byte order {byte_order} in log header")
+            }
+            Error::UnsupportedLayout(header) => write!(
+                f,
+                "unsupported record layout for version {}: {}/{}/{} byte language/project/timestamp",
+                header.version, header.language_width, header.project_width, header.timestamp_width
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+/// Counts how many bytes have been read so far, so the end of the last
+/// complete record is known.
+struct CountingReader<R> {
+    inner: R,
+    read: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let amount = self.inner.read(buf)?;
+        self.read += amount as u64;
+        Ok(amount)
+    }
+}
+
+/// A change [`recover`] made to bring the log back into a consistent state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// The file ended in the middle of a record, so the partial record was
+    /// cut off.
+    Truncated { len: u64, valid_len: u64 },
+    /// The last session had no end record, so one ending at its start was
+    /// added.
+    ClosedSession { time: i64 },
+}
+
+impl Display for Recovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Recovery::Truncated { len, valid_len } => write!(
+                f,
+                "removed {} bytes of an incomplete record at the end of the log",
+                len - valid_len
+            ),
+            Recovery::ClosedSession { time } => write!(
+                f,
+                "added missing end record to the session started at {time}"
+            ),
+        }
+    }
+}
+
+/// Validates the records in the log at `path`, which must already be in the
+/// current format, and repairs a torn tail left behind by a crash in the
+/// middle of a write. Returns the repairs that were made.
+pub fn recover(path: &Path, header: &Header) -> Result<Vec<Recovery>, Error> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let len = file.metadata()?.len();
+
+    file.seek(SeekFrom::Start(HEADER_LEN as u64))?;
+    let mut reader = CountingReader {
+        inner: BufReader::new(&file),
+        read: HEADER_LEN as u64,
+    };
+
+    let mut valid_len = reader.read;
+    let mut last_record = None;
+    loop {
+        match Record::read(&mut reader, header) {
+            Ok(Some(record)) => {
+                valid_len = reader.read;
+                last_record = Some(record);
+            }
+            Ok(None) | Err(Error::Truncated) => break,
+            Err(err) => return Err(err),
+        }
+    }
+
+    let mut repairs = Vec::new();
+
+    if valid_len < len {
+        file.set_len(valid_len)?;
+        repairs.push(Recovery::Truncated { len, valid_len });
+    }
+
+    if let Some(Record::Start { time, .. }) = last_record {
+        file.seek(SeekFrom::End(0))?;
+        file.write_all(&Record::End { time }.encode(header.byte_order))?;
+        repairs.push(Recovery::ClosedSession { time });
+    }
+
+    if !repairs.is_empty() {
+        file.sync_all()?;
+    }
+
+    Ok(repairs)
+}
+
+/// Writes the records of the log at `path` to `tmp_path` in the current
+/// format. Like [`recover`], this stops at a record cut off at the end, so
+/// logs torn by a crash can still be rewritten.
+fn rewrite_records(
+    path: &Path,
+    header: &Header,
+    tmp_path: &Path,
+    mut map: impl FnMut(Record) -> Record,
+) -> Result<(), Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    if header.version != 0 {
+        reader.seek(SeekFrom::Start(HEADER_LEN as u64))?;
+    }
+    let mut writer = BufWriter::new(File::create(tmp_path)?);
+
+    writer.write_all(&Header::CURRENT.encode())?;
+    loop {
+        match Record::read(&mut reader, header) {
+            Ok(Some(record)) => {
+                writer.write_all(&map(record).encode(Header::CURRENT.byte_order))?
+            }
+            Ok(None) => break,
+            Err(Error::Truncated) => {
+                warn!(?path, "dropped an incomplete record at the end of the log");
+                break;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    writer
+        .into_inner()
+        .map_err(|err| err.into_error())?
+        .sync_all()?;
+
+    Ok(())
+}
+
+/// Rewrites the log at `path`, which was written with `header`, in the
+/// current format, passing every record through `map` on the way. The
+/// original file is kept at `backup_path`, which must not exist yet so that
+/// an earlier backup is never replaced.
+pub fn rewrite(
+    path: &Path,
+    header: &Header,
+    backup_path: &Path,
+    map: impl FnMut(Record) -> Record,
+) -> Result<(), Error> {
+    let tmp_path = path.with_extension("tmp");
+
+    if let Err(err) = rewrite_records(path, header, &tmp_path, map) {
+        // The original is untouched, so the partial copy is of no use.
+        let _ = fs::remove_file(&tmp_path);
+        return Err(err);
+    }
+
+    // Linking fails instead of replacing an existing file, and the log is
+    // never missing in between.
+    if let Err(err) = fs::hard_link(path, backup_path) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(err.into());
+    }
+    fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
+/// Rewrites the log at `path`, which was written with `header`, in the
+/// current format. The original file is kept next to it as
+/// `<name>.v<version>.bak`, and its path is returned.
+pub fn migrate(path: &Path, header: &Header) -> Result<PathBuf, Error> {
+    let backup_path = path.with_extension(format!("v{}.bak", header.version));
+    rewrite(path, header, &backup_path, |record| record)?;
+    Ok(backup_path)
+}
+
+/// Makes sure the log at `path` exists and is in the current format,
+/// creating it or migrating an older log as needed, and returns its header.
+pub fn prepare(path: &Path) -> Result<Header, Error> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(path)?;
+
+    if file.metadata()?.len() == 0 {
+        file.write_all(&Header::CURRENT.encode())?;
+        file.sync_all()?;
+        return Ok(Header::CURRENT);
+    }
+
+    let header = Header::read(&mut file)?;
+    if header.version == VERSION {
+        return Ok(header);
+    }
+
+    drop(file);
+    let backup_path = migrate(path, &header)?;
+    info!(
+        ?path,
+        ?backup_path,
+        from = header.version,
+        to = VERSION,
+        "migrated log file"
+    );
+
+    Ok(Header::CURRENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn read_all(bytes: &[u8], header: &Header) -> Result<Vec<Record>, Error> {
+        let mut reader = Cursor::new(bytes);
+        let mut records = Vec::new();
+        while let Some(record) = Record::read(&mut reader, header)? {
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    fn start(language: usize, project: usize, time: i64) -> Record {
+        Record::Start {
+            language,
+            project,
+            file: None,
+            branch: None,
+            time,
+        }
+    }
+
+    #[test]
+    fn header_round_trips() {
+        assert_eq!(
+            Header::decode(&Header::CURRENT.encode()).unwrap(),
+            Some(Header::CURRENT)
+        );
+    }
+
+    #[test]
+    fn header_without_magic_is_legacy() {
+        assert_eq!(Header::decode(&[0; HEADER_LEN]).unwrap(), None);
+
+        // Shorter than a header, so the start of a legacy record.
+        let mut reader = Cursor::new(vec![1, 0, 0]);
+        assert_eq!(Header::read(&mut reader).unwrap(), Header::LEGACY);
+        assert_eq!(reader.position(), 0);
+    }
+
+    #[test]
+    fn header_decode_errors() {
+        let mut bytes = Header::CURRENT.encode();
+        bytes[8] = VERSION + 1;
+        assert!(matches!(
+            Header::decode(&bytes),
+            Err(Error::UnsupportedVersion(version)) if version == VERSION + 1
+        ));
+
+        bytes[8] = 0;
+        assert!(matches!(
+            Header::decode(&bytes),
+            Err(Error::UnsupportedVersion(0))
+        ));
+
+        let mut bytes = Header::CURRENT.encode();
+        bytes[9] = 2;
+        assert!(matches!(
+            Header::decode(&bytes),
+            Err(Error::InvalidByteOrder(2))
+        ));
+
+        let mut bytes = Header::CURRENT.encode();
+        bytes[10] = 1;
+        assert!(matches!(
+            Header::decode(&bytes),
+            Err(Error::UnsupportedLayout(_))
+        ));
+    }
+
+    #[test]
+    fn decodes_legacy_records() {
+        let bytes = [
+            &[3][..],
+            &7u16.to_ne_bytes(),
+            &100i64.to_ne_bytes(),
+            &[0],
+            &160i64.to_ne_bytes(),
+        ]
+        .concat();
+
+        assert_eq!(
+            read_all(&bytes, &Header::LEGACY).unwrap(),
+            vec![start(2, 7, 100), Record::End { time: 160 }]
+        );
+    }
+
+    #[test]
+    fn decodes_version_1_records() {
+        let header = Header {
+            version: 1,
+            byte_order: ByteOrder::Big,
+            language_width: 1,
+            project_width: 2,
+            timestamp_width: 8,
+        };
+        assert_eq!(Header::decode(&header.encode()).unwrap(), Some(header));
+
+        let bytes = [
+            &[1][..],
+            &300u16.to_be_bytes(),
+            &100i64.to_be_bytes(),
+            &[0],
+            &160i64.to_be_bytes(),
+        ]
+        .concat();
+
+        assert_eq!(
+            read_all(&bytes, &header).unwrap(),
+            vec![start(0, 300, 100), Record::End { time: 160 }]
+        );
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        for value in [0, 1, 0x7f, 0x80, 0x3fff, 0x4000, usize::MAX] {
+            let mut bytes = Vec::new();
+            encode_varint(&mut bytes, value as u64);
+            assert!(bytes.len() <= MAX_VARINT_LEN);
+            assert_eq!(read_varint(&mut Cursor::new(&bytes)).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn overlong_varints_are_rejected() {
+        assert!(matches!(
+            read_varint(&mut Cursor::new([0xff; MAX_VARINT_LEN + 1])),
+            Err(Error::InvalidVarint)
+        ));
+
+        let mut bytes = vec![0xff; MAX_VARINT_LEN - 1];
+        bytes.push(0x02);
+        assert!(matches!(
+            read_varint(&mut Cursor::new(bytes)),
+            Err(Error::InvalidVarint)
+        ));
+
+        assert!(matches!(
+            read_varint(&mut Cursor::new([0x80])),
+            Err(Error::Truncated)
+        ));
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let records = [
+            start(0, 0, 0),
+            Record::Start {
+                language: 200,
+                project: 70_000,
+                file: Some(0),
+                branch: Some(3),
+                time: -5,
+            },
+            Record::End { time: i64::MAX },
+        ];
+
+        for byte_order in [ByteOrder::Little, ByteOrder::Big] {
+            let header = Header {
+                byte_order,
+                ..Header::CURRENT
+            };
+            let bytes = records
+                .iter()
+                .flat_map(|record| record.encode(byte_order))
+                .collect::<Vec<_>>();
+            assert_eq!(read_all(&bytes, &header).unwrap(), records);
+        }
+    }
+
+    /// Writes `bytes` to a file of its own in the temporary directory.
+    fn temp_log(name: &str, bytes: &[u8]) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("code-statistics-{}-{name}", std::process::id()));
+        fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    fn read_log(path: &Path) -> Vec<Record> {
+        let bytes = fs::read(path).unwrap();
+        let header = Header::decode(bytes[..HEADER_LEN].try_into().unwrap())
+            .unwrap()
+            .unwrap();
+        read_all(&bytes[HEADER_LEN..], &header).unwrap()
+    }
+
+    #[test]
+    fn recover_truncates_and_closes_torn_tail() {
+        let byte_order = Header::CURRENT.byte_order;
+        let mut bytes = Header::CURRENT.encode().to_vec();
+        bytes.extend(start(0, 0, 100).encode(byte_order));
+        bytes.extend(Record::End { time: 160 }.encode(byte_order));
+        bytes.extend(start(1, 0, 200).encode(byte_order));
+        // The crash happened while the end record was being written.
+        bytes.extend(&Record::End { time: 260 }.encode(byte_order)[..4]);
+        let path = temp_log("torn", &bytes);
+
+        let repairs = recover(&path, &Header::CURRENT).unwrap();
+        assert_eq!(
+            repairs,
+            vec![
+                Recovery::Truncated {
+                    len: bytes.len() as u64,
+                    valid_len: bytes.len() as u64 - 4,
+                },
+                Recovery::ClosedSession { time: 200 },
+            ]
+        );
+        assert_eq!(
+            read_log(&path),
+            vec![
+                start(0, 0, 100),
+                Record::End { time: 160 },
+                start(1, 0, 200),
+                Record::End { time: 200 },
+            ]
+        );
+
+        assert_eq!(recover(&path, &Header::CURRENT).unwrap(), vec![]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn migrate_drops_torn_tail_of_legacy_log() {
+        let bytes = [
+            &[1][..],
+            &0u16.to_ne_bytes(),
+            &100i64.to_ne_bytes(),
+            &[0],
+            &160i64.to_ne_bytes(),
+            // Cut off in the middle of the next start record.
+            &[1, 0, 0],
+        ]
+        .concat();
+        let path = temp_log("legacy", &bytes);
+
+        let backup_path = migrate(&path, &Header::LEGACY).unwrap();
+        assert_eq!(fs::read(&backup_path).unwrap(), bytes);
+        assert!(!path.with_extension("tmp").exists());
+        assert_eq!(
+            read_log(&path),
+            vec![start(0, 0, 100), Record::End { time: 160 }]
+        );
+
+        fs::remove_file(path).unwrap();
+        fs::remove_file(backup_path).unwrap();
+    }
+}
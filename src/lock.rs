@@ -0,0 +1,37 @@
+//! The daemon keeps the tags in memory and the log open, so commands that
+//! rewrite them must not run alongside it. It holds an exclusive lock on a
+//! file in the data directory for as long as it runs, which those commands
+//! take instead of connecting to the socket, since connecting would start a
+//! socket activated daemon.
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io,
+    path::PathBuf,
+};
+
+use crate::paths::data_dir;
+
+pub fn lock_path() -> PathBuf {
+    data_dir().join("lock")
+}
+
+/// Holds the lock until dropped.
+pub struct DataLock {
+    _file: File,
+}
+
+/// Takes the lock, returning `None` when another process holds it. Fails
+/// with [`io::ErrorKind::NotFound`] when the data directory doesn't exist.
+pub fn try_lock() -> io::Result<Option<DataLock>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock_path())?;
+
+    match file.try_lock() {
+        Ok(()) => Ok(Some(DataLock { _file: file })),
+        Err(TryLockError::WouldBlock) => Ok(None),
+        Err(TryLockError::Error(err)) => Err(err),
+    }
+}
@@ -1,29 +1,37 @@
-use std::io::SeekFrom;
+use std::{
+    io::SeekFrom,
+    os::fd::OwnedFd,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
 use chrono::{DateTime, Utc};
-use dirs::data_dir;
 use futures_concurrency::future::Race;
 use smol::{
     fs::{File, OpenOptions},
     io::{AsyncSeekExt, AsyncWriteExt},
     stream::StreamExt,
-    LocalExecutor, Timer,
+    unblock, LocalExecutor, Task, Timer,
 };
-use tracing::{debug, span, trace, Instrument, Level};
+use tracing::{debug, info, span, trace, warn, Instrument, Level};
 
 use crate::{
     channel,
-    config::Config,
+    config::{FsyncPolicy, SharedConfig},
     debounce::{debounce, LogMessage},
-    Sender,
+    format::{self, Header, Record},
+    paths::data_dir,
+    Receiver, Sender,
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Status {
     Active {
         time: DateTime<Utc>,
         language: usize,
         project: usize,
+        file: Option<usize>,
+        branch: Option<usize>,
     },
     Dormant {
         time: DateTime<Utc>,
@@ -32,8 +40,80 @@ pub enum Status {
 
 #[derive(Debug)]
 pub enum SystemMessage {
-    Suspend { time: DateTime<Utc> },
+    Suspend {
+        time: DateTime<Utc>,
+    },
     Resume,
+    /// The screen of the login session was locked.
+    Lock {
+        time: DateTime<Utc>,
+    },
+    Unlock,
+    /// logind considers the login session idle, starting at `since`.
+    Idle {
+        since: DateTime<Utc>,
+    },
+    Unidle,
+    /// The system is about to shut down or reboot. `inhibitor` holds the
+    /// shutdown back and is closed once the log is on disk.
+    Shutdown {
+        time: DateTime<Utc>,
+        inhibitor: Option<OwnedFd>,
+    },
+    ShutdownCancelled,
+    /// The daemon is stopping. Anything already sent is still written, then
+    /// the session is closed at `time`.
+    Stop {
+        time: DateTime<Utc>,
+    },
+}
+
+/// Why sessions aren't being recorded. Recording only starts again once none
+/// of these hold.
+#[derive(Debug, Default, Clone, Copy)]
+struct Paused {
+    suspended: bool,
+    locked: bool,
+    idle: bool,
+    shutting_down: bool,
+}
+
+impl Paused {
+    fn any(self) -> bool {
+        self.suspended || self.locked || self.idle || self.shutting_down
+    }
+}
+
+/// How far the wall clock may drift from the monotonic clock between two
+/// writes of the end of a session before the time in between is dropped.
+const MAX_CLOCK_DRIFT: Duration = Duration::from_secs(30);
+
+/// When the end of the open session was last written, on both clocks.
+#[derive(Debug, Clone, Copy)]
+struct Written {
+    monotonic: Instant,
+    wall: DateTime<Utc>,
+}
+
+impl Written {
+    fn now() -> Self {
+        Written {
+            monotonic: Instant::now(),
+            wall: Utc::now(),
+        }
+    }
+
+    /// Returns whether the wall clock jumped between `self` and `now`. The
+    /// monotonic clock stops while the computer is suspended, so this also
+    /// catches suspends logind didn't report.
+    fn jumped(self, now: Written) -> bool {
+        let monotonic = now.monotonic.saturating_duration_since(self.monotonic);
+        match (now.wall - self.wall).to_std() {
+            Ok(wall) => wall.abs_diff(monotonic) > MAX_CLOCK_DRIFT,
+            // The clock was set back.
+            Err(_) => true,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -45,85 +125,167 @@ pub enum Message {
 
 async fn send_start_event(
     file: &mut File,
+    header: &Header,
     language_idx: usize,
     project_idx: usize,
+    file_idx: Option<usize>,
+    branch_idx: Option<usize>,
     timestamp: DateTime<Utc>,
 ) {
     trace!("sending start event");
-    file.write_all(&[(language_idx + 1)
-        .try_into()
-        .expect("Cannot support more that 255 languages")])
-        .await
-        .expect("Failed to write to log file");
-    file.write_all(
-        &TryInto::<u16>::try_into(project_idx)
-            .expect("Cannot support more than 65536 projects")
-            .to_ne_bytes(),
-    )
-    .await
-    .expect("Failed to write to log file");
-    file.write_all(&timestamp.timestamp().to_ne_bytes())
+    // Both records go out in one write so a crash can't leave a start record
+    // without an end record behind it.
+    let mut bytes = Record::Start {
+        language: language_idx,
+        project: project_idx,
+        file: file_idx,
+        branch: branch_idx,
+        time: timestamp.timestamp(),
+    }
+    .encode(header.byte_order);
+    bytes.extend(
+        Record::End {
+            time: timestamp.timestamp(),
+        }
+        .encode(header.byte_order),
+    );
+    file.write_all(&bytes)
         .await
         .expect("Failed to write to log file");
 
-    file.write_all(&[0])
-        .await
-        .expect("Failed to write to log file");
-    file.write_all(&timestamp.timestamp().to_ne_bytes())
-        .await
-        .expect("Failed to write to log file");
-
-    file.seek(SeekFrom::End(-(size_of::<i64>() as i64 + 1)))
+    file.seek(SeekFrom::End(-(Record::END_LEN as i64)))
         .await
         .expect("Failed to write to log file");
 }
 
-async fn send_stop_event(file: &mut File, timestamp: DateTime<Utc>) {
+async fn send_stop_event(file: &mut File, header: &Header, timestamp: DateTime<Utc>) {
     trace!("sending stop event");
-    file.seek(SeekFrom::End(-(size_of::<i64>() as i64)))
-        .await
-        .expect("Failed to write to log file");
-    file.write_all(&timestamp.timestamp().to_ne_bytes())
+    file.seek(SeekFrom::End(-(Record::END_LEN as i64)))
         .await
         .expect("Failed to write to log file");
+    file.write_all(
+        &Record::End {
+            time: timestamp.timestamp(),
+        }
+        .encode(header.byte_order),
+    )
+    .await
+    .expect("Failed to write to log file");
+}
+
+/// Syncs the log file to disk if `policy` asks for it. `boundary` is set when
+/// the write started or ended a session.
+async fn sync(file: &File, policy: FsyncPolicy, boundary: bool) {
+    let should_sync = match policy {
+        FsyncPolicy::Never => false,
+        FsyncPolicy::Session => boundary,
+        FsyncPolicy::Always => true,
+    };
+
+    if should_sync {
+        trace!("syncing log file");
+        file.sync_data().await.expect("Failed to sync log file");
+    }
+}
+
+/// Writes the end of the open session at `time`, if there is one. Without an
+/// open session the last record is the end of an earlier one, or the header
+/// of an empty log, so there is nothing to overwrite.
+async fn end_session(
+    file: &mut File,
+    header: &Header,
+    fsync: FsyncPolicy,
+    status_sender: &Sender<Status, 5>,
+    last_message: &mut Option<(usize, usize, Option<usize>, Option<usize>)>,
+    time: DateTime<Utc>,
+) {
+    if last_message.take().is_some() {
+        send_stop_event(file, header, time).await;
+        sync(file, fsync, true).await;
+        status_sender.send(Status::Dormant { time });
+    }
 }
 
+/// Writes out and syncs everything written so far, whatever the fsync
+/// policy.
+async fn flush(file: &mut File) {
+    file.flush().await.expect("Failed to write to log file");
+    file.sync_all().await.expect("Failed to sync log file");
+}
+
+/// Lets sessions be recorded again once nothing pauses them. Clients keep
+/// sending the session they were in, which has to count as a new one now.
+fn resume(paused: Paused, debounce_input: &Sender<LogMessage, 5>) {
+    if !paused.any() {
+        debounce_input.send(LogMessage::ResetStatus);
+    }
+}
+
+pub fn log_path() -> PathBuf {
+    data_dir().join("log")
+}
+
+/// Spawns the log task. Besides the senders for messages, this returns a
+/// receiver that gets the status whenever a session starts or ends, and the
+/// task itself, which finishes once [`SystemMessage::Stop`] is handled.
 pub fn log(
     executor: &LocalExecutor<'_>,
-    config: &'static Config,
-) -> (Sender<LogMessage, 5>, Sender<SystemMessage, 5>) {
+    config: SharedConfig,
+) -> (
+    Sender<LogMessage, 5>,
+    Sender<SystemMessage, 5>,
+    Receiver<Status, 5>,
+    Task<()>,
+) {
     let (system_sender, mut system_receiver) = channel();
+    let (status_sender, status_receiver) = channel();
 
-    let (sender, mut receiver) = debounce(config.debounce_amount, executor);
+    let (sender, mut receiver) = debounce(config.clone(), executor);
 
     let debounce_input = sender.clone();
 
-    executor
-        .spawn(
-            async move {
-                let mut file_path = data_dir().expect("Failed to find data directory");
-                file_path.push("code-statistics");
-                file_path.push("log");
+    let task = executor.spawn(
+        async move {
+            let header = unblock(|| {
+                let path = log_path();
+                let header = format::prepare(&path)?;
+                for repair in format::recover(&path, &header)? {
+                    warn!("Repaired log file: {repair}");
+                }
+                Ok::<_, format::Error>(header)
+            })
+            .await
+            .unwrap_or_else(|err| panic!("Failed to open log file: {err}"));
+
+            let mut file = OpenOptions::new()
+                .write(true)
+                .read(true)
+                .open(log_path())
+                .await
+                .expect("Failed to open log file");
 
-                let mut file = OpenOptions::new()
-                    .create(true)
-                    .write(true)
-                    .read(true)
-                    .open(file_path)
-                    .await
-                    .expect("Failed to open log file");
+            file.seek(SeekFrom::End(0))
+                .await
+                .expect("Failed to write to log file");
 
-                file.seek(SeekFrom::End(0))
-                    .await
-                    .expect("Failed to write to log file");
+            let mut last_message = None;
+            let mut started = Utc::now();
+            let mut written = Written::now();
+            let mut paused = Paused::default();
+            let mut stopping = None;
 
-                let mut last_message = None;
-                let mut suspended = false;
+            trace!("completed set up");
 
-                trace!("completed set up");
+            loop {
+                let config = config.get();
 
-                loop {
-                    let message = (
+                let message = if stopping.is_some() {
+                    receiver
+                        .try_recv()
+                        .map(Message::Status)
+                        .or_else(|| system_receiver.try_recv().map(Message::System))
+                } else {
+                    (
                         async { Some(Message::Status(receiver.next().await?)) },
                         async { Some(Message::System(system_receiver.next().await?)) },
                         async {
@@ -138,56 +300,214 @@ pub fn log(
                         },
                     )
                         .race()
-                        .await;
+                        .await
+                };
 
-                    let Some(message) = message else {
+                let Some(message) = message else {
+                    if stopping.is_none() {
                         trace!("stopping because a channel disconnected");
-                        break;
-                    };
+                    }
+                    break;
+                };
 
-                    debug!(?message, ?last_message, suspended);
+                debug!(?message, ?last_message, ?paused);
 
-                    match message {
-                        Message::System(SystemMessage::Suspend { time }) => {
-                            send_stop_event(&mut file, time).await;
-                            last_message = None;
-                            suspended = true;
-                        }
-                        Message::System(SystemMessage::Resume) => {
-                            suspended = false;
-                            debounce_input.send(LogMessage::ResetStatus);
-                        }
-                        _ if suspended => {}
+                match message {
+                    Message::System(SystemMessage::Suspend { time }) => {
+                        paused.suspended = true;
+                        end_session(
+                            &mut file,
+                            &header,
+                            config.fsync,
+                            &status_sender,
+                            &mut last_message,
+                            time,
+                        )
+                        .await;
+                    }
+                    Message::System(SystemMessage::Lock { time }) => {
+                        paused.locked = true;
+                        end_session(
+                            &mut file,
+                            &header,
+                            config.fsync,
+                            &status_sender,
+                            &mut last_message,
+                            time,
+                        )
+                        .await;
+                    }
+                    Message::System(SystemMessage::Idle { since }) => {
+                        paused.idle = true;
+                        // logind may have seen no input for longer than
+                        // the session has been going.
+                        end_session(
+                            &mut file,
+                            &header,
+                            config.fsync,
+                            &status_sender,
+                            &mut last_message,
+                            since.max(started),
+                        )
+                        .await;
+                    }
+                    Message::System(SystemMessage::Shutdown { time, inhibitor }) => {
+                        paused.shutting_down = true;
+                        end_session(
+                            &mut file,
+                            &header,
+                            config.fsync,
+                            &status_sender,
+                            &mut last_message,
+                            time,
+                        )
+                        .await;
+                        flush(&mut file).await;
+                        drop(inhibitor);
+                    }
+                    Message::System(SystemMessage::Stop { time }) => {
+                        stopping = stopping.or(Some(time));
+                    }
+                    Message::System(SystemMessage::Resume) => {
+                        paused.suspended = false;
+                        resume(paused, &debounce_input);
+                    }
+                    Message::System(SystemMessage::Unlock) => {
+                        paused.locked = false;
+                        resume(paused, &debounce_input);
+                    }
+                    Message::System(SystemMessage::Unidle) => {
+                        paused.idle = false;
+                        resume(paused, &debounce_input);
+                    }
+                    Message::System(SystemMessage::ShutdownCancelled) => {
+                        paused.shutting_down = false;
+                        resume(paused, &debounce_input);
+                    }
+                    _ if paused.any() => {}
+
+                    Message::Heartbeat => {
+                        let now = Written::now();
 
-                        Message::Heartbeat => {
-                            send_stop_event(&mut file, Utc::now()).await;
-                            file.seek(SeekFrom::End(-(size_of::<i64>() as i64 + 1)))
+                        if written.jumped(now) {
+                            // Whatever happened in between, the session was
+                            // last known to be going when its end was written.
+                            // The editor is still sending heartbeats for it,
+                            // so it goes on from now as a new session.
+                            warn!(
+                                last = %written.wall,
+                                now = %now.wall,
+                                "the clock jumped since the last heartbeat, starting the session over"
+                            );
+                            let current = last_message;
+                            end_session(
+                                &mut file,
+                                &header,
+                                config.fsync,
+                                &status_sender,
+                                &mut last_message,
+                                written.wall,
+                            )
+                            .await;
+
+                            if let Some((language, project, source_file, branch)) = current {
+                                send_start_event(
+                                    &mut file,
+                                    &header,
+                                    language,
+                                    project,
+                                    source_file,
+                                    branch,
+                                    now.wall,
+                                )
+                                .await;
+                                sync(&file, config.fsync, true).await;
+                                started = now.wall;
+                                status_sender.send(Status::Active {
+                                    time: now.wall,
+                                    language,
+                                    project,
+                                    file: source_file,
+                                    branch,
+                                });
+                                last_message = current;
+                            }
+                        } else {
+                            send_stop_event(&mut file, &header, now.wall).await;
+                            sync(&file, config.fsync, false).await;
+                            file.seek(SeekFrom::End(-(Record::END_LEN as i64)))
                                 .await
                                 .expect("Failed to write to log file");
                         }
 
-                        Message::Status(Status::Active {
-                            time,
-                            language,
-                            project,
-                        }) => {
-                            if last_message
-                                .is_none_or(|last_message| last_message != (language, project))
-                            {
-                                send_start_event(&mut file, language, project, time).await;
-                                last_message = Some((language, project));
-                            }
-                        }
-                        Message::Status(Status::Dormant { time }) => {
-                            send_stop_event(&mut file, time).await;
-                            last_message = None;
+                        written = now;
+                    }
+
+                    Message::Status(Status::Active {
+                        time,
+                        language,
+                        project,
+                        file: source_file,
+                        branch,
+                    }) => {
+                        if last_message.is_none_or(|last_message| {
+                            last_message != (language, project, source_file, branch)
+                        }) {
+                            send_start_event(
+                                &mut file,
+                                &header,
+                                language,
+                                project,
+                                source_file,
+                                branch,
+                                time,
+                            )
+                            .await;
+                            sync(&file, config.fsync, true).await;
+                            started = time;
+                            // Not `time`, which is already as old as the
+                            // debounce delay.
+                            written = Written::now();
+                            status_sender.send(Status::Active {
+                                time,
+                                language,
+                                project,
+                                file: source_file,
+                                branch,
+                            });
+                            last_message = Some((language, project, source_file, branch));
                         }
                     }
+                    Message::Status(Status::Dormant { time }) => {
+                        end_session(
+                            &mut file,
+                            &header,
+                            config.fsync,
+                            &status_sender,
+                            &mut last_message,
+                            time,
+                        )
+                        .await;
+                    }
                 }
             }
-            .instrument(span!(Level::DEBUG, "log task")),
-        )
-        .detach();
 
-    (sender, system_sender)
+            if let Some(time) = stopping {
+                end_session(
+                    &mut file,
+                    &header,
+                    config.get().fsync,
+                    &status_sender,
+                    &mut last_message,
+                    time,
+                )
+                .await;
+                flush(&mut file).await;
+                info!("closed the log");
+            }
+        }
+        .instrument(span!(Level::DEBUG, "log task")),
+    );
+
+    (sender, system_sender, status_receiver, task)
 }
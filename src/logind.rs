@@ -0,0 +1,206 @@
+//! Follows logind to end sessions when the computer suspends or shuts down,
+//! or the login session is locked or left idle.
+//!
+//! logind isn't always there, like in containers or minimal sessions, and the
+//! system bus can restart. Connecting is retried with a growing delay, and in
+//! the meantime the log task still notices suspends from jumps of the wall
+//! clock.
+use std::{
+    future::pending,
+    pin::pin,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use futures_concurrency::{future::Race, stream::Merge};
+use smol::{stream::StreamExt, Timer};
+use tracing::{info, trace, warn};
+use zbus::{zvariant::OwnedFd, Connection};
+
+use crate::{log::SystemMessage, manager::ManagerProxy, session::SessionProxy, Sender};
+
+/// Delay before the first retry, doubled after every failed attempt.
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Changes to the login session that pause recording.
+#[derive(Debug)]
+enum SessionEvent {
+    Lock,
+    Unlock,
+    IdleHint(bool),
+}
+
+async fn inhibit(manager: &ManagerProxy<'_>, what: &str) -> zbus::Result<OwnedFd> {
+    manager
+        .inhibit(what, "code-statistics", "Logging end of activity", "delay")
+        .await
+}
+
+/// Returns once the signals stop, which happens when the connection is lost.
+async fn follow_sleep(
+    manager: &ManagerProxy<'_>,
+    system: &Sender<SystemMessage, 5>,
+) -> zbus::Result<()> {
+    let mut stream = manager.receive_prepare_for_sleep().await?;
+    let mut inhibit_fd = Some(inhibit(manager, "sleep").await?);
+
+    trace!("following sleep");
+
+    while let Some(is_sleeping) = stream.next().await {
+        let args = is_sleeping.args()?;
+
+        trace!(name = "received sleep signal", ?args);
+
+        if !args.start {
+            system.send(SystemMessage::Resume);
+            inhibit_fd = Some(inhibit(manager, "sleep").await?);
+        } else {
+            system.send(SystemMessage::Suspend { time: Utc::now() });
+            drop(inhibit_fd.take());
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns once the signals stop, which happens when the connection is lost.
+async fn follow_shutdown(
+    manager: &ManagerProxy<'_>,
+    system: &Sender<SystemMessage, 5>,
+) -> zbus::Result<()> {
+    let mut stream = manager.receive_prepare_for_shutdown().await?;
+    let mut inhibit_fd = Some(inhibit(manager, "shutdown").await?);
+
+    trace!("following shutdown");
+
+    while let Some(is_shutting_down) = stream.next().await {
+        let args = is_shutting_down.args()?;
+
+        trace!(name = "received shutdown signal", ?args);
+
+        if args.start {
+            // The log task closes the inhibitor once the log is synced.
+            system.send(SystemMessage::Shutdown {
+                time: Utc::now(),
+                inhibitor: inhibit_fd.take().map(Into::into),
+            });
+        } else {
+            system.send(SystemMessage::ShutdownCancelled);
+            inhibit_fd = Some(inhibit(manager, "shutdown").await?);
+        }
+    }
+
+    Ok(())
+}
+
+async fn follow_session(
+    connection: &Connection,
+    manager: &ManagerProxy<'_>,
+    system: &Sender<SystemMessage, 5>,
+) -> zbus::Result<()> {
+    // The daemon runs as a user service outside of any login session, and
+    // "auto" falls back to the graphical session of the user.
+    let path = manager.get_session("auto").await?;
+    let session = SessionProxy::builder(connection)
+        .path(path)?
+        .build()
+        .await?;
+
+    let locks = session
+        .receive_lock()
+        .await?
+        .map(|_| Ok(SessionEvent::Lock));
+    let unlocks = session
+        .receive_unlock()
+        .await?
+        .map(|_| Ok(SessionEvent::Unlock));
+    let idle_hints = session
+        .receive_idle_hint_changed()
+        .await
+        .then(|change| async move { change.get().await.map(SessionEvent::IdleHint) });
+    let mut events = pin!((locks, unlocks, idle_hints).merge());
+
+    // The lock may have changed while the connection was lost, so the state
+    // is sent either way.
+    if session.locked_hint().await? {
+        system.send(SystemMessage::Lock { time: Utc::now() });
+    } else {
+        system.send(SystemMessage::Unlock);
+    }
+
+    trace!("following the login session");
+
+    while let Some(event) = events.next().await {
+        let event = event?;
+
+        trace!(name = "received session event", ?event);
+
+        match event {
+            SessionEvent::Lock => system.send(SystemMessage::Lock { time: Utc::now() }),
+            SessionEvent::Unlock => system.send(SystemMessage::Unlock),
+            SessionEvent::IdleHint(false) => system.send(SystemMessage::Unidle),
+            SessionEvent::IdleHint(true) => {
+                // IdleSinceHint is in microseconds, and 0 when logind doesn't
+                // know.
+                let since = session
+                    .idle_since_hint()
+                    .await
+                    .ok()
+                    .filter(|since| *since != 0)
+                    .and_then(|since| DateTime::from_timestamp_micros(since.try_into().ok()?))
+                    .unwrap_or_else(Utc::now);
+                system.send(SystemMessage::Idle { since });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns when the connection to logind is lost.
+async fn follow(system: &Sender<SystemMessage, 5>) -> zbus::Result<()> {
+    let connection = Connection::system().await?;
+    let manager = ManagerProxy::new(&connection).await?;
+
+    // Not every user has a login session, like when the daemon is started
+    // over ssh, which shouldn't stop suspends from being followed.
+    let session = async {
+        if let Err(err) = follow_session(&connection, &manager, system).await {
+            warn!(%err, "Failed to follow the login session, locking it won't end sessions");
+        }
+        pending().await
+    };
+
+    (
+        follow_sleep(&manager, system),
+        follow_shutdown(&manager, system),
+        session,
+    )
+        .race()
+        .await
+}
+
+/// Follows logind for as long as the daemon runs, holding on to `system` so
+/// the log task keeps running.
+pub async fn watch(system: Sender<SystemMessage, 5>) {
+    let mut delay = MIN_RETRY_DELAY;
+
+    loop {
+        let attempt = Instant::now();
+        match follow(&system).await {
+            Ok(()) => warn!("Lost the connection to logind"),
+            Err(err) => warn!(%err, "Failed to follow logind"),
+        }
+
+        // Only keep backing off while logind fails straight away.
+        if attempt.elapsed() > delay {
+            delay = MIN_RETRY_DELAY;
+        }
+
+        info!(?delay, "retrying logind");
+        Timer::after(delay).await;
+
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+    }
+}
@@ -1,250 +1,835 @@
 use std::{
     cell::Cell,
-    future::poll_fn,
-    io::ErrorKind,
-    os::fd::{FromRawFd, OwnedFd},
-    ptr,
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    future::pending,
+    io::{BufReader, ErrorKind},
+    os::fd::{BorrowedFd, FromRawFd, OwnedFd},
+    panic::AssertUnwindSafe,
+    path::PathBuf,
+    process::ExitCode,
     rc::Rc,
-    task::Poll,
+    sync::Arc,
 };
 
-use chrono::Utc;
+use async_signal::{Signal, Signals};
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use clap::{Parser, Subcommand, ValueEnum};
 use code_statistics::{
-    config::{read_config, Config},
-    debounce::LogMessage,
-    log::log,
-    manager::ManagerProxy,
-    sd_is_socket_unix, sd_listen_fds,
-    tags::Tags,
-    SD_LISTEN_FDS_START,
+    activation,
+    client::Client,
+    config::{load_config, reload_on_hangup, Config, SharedConfig},
+    connection::{self, Shared},
+    doctor,
+    export::Format,
+    format::{self, Header, Record},
+    lock::{self, lock_path, DataLock},
+    log::{log, log_path, SystemMessage},
+    logind,
+    notify::{self, notify},
+    paths::{self, config_path, data_dir, find_socket_path, socket_path, Paths},
+    projects::{normalize_identity, Project, Projects},
+    protocol::{Period, Request, Response},
+    reader::{self, RawReader, Session},
+    report::{format_duration, Range, Report},
+    service::{self, CurrentStatus},
+    tags::{tags_path, write_tags, Tags},
 };
-use dirs::data_dir;
 use futures_concurrency::future::Race;
-use smol::{
-    fs::create_dir_all,
-    io::{AsyncBufReadExt, BufReader},
-    net::unix::UnixListener,
-    stream::StreamExt,
-    unblock, Timer,
-};
-use tracing::{debug, info, span, trace, warn, Instrument, Level};
+use parking_lot::Mutex;
+use smol::{future::FutureExt, net::unix::UnixListener, stream::StreamExt};
+use tracing::{debug, error, info, span, warn, Instrument, Level};
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
-use zbus::Connection;
 
-fn main() -> Result<(), ()> {
+/// Records the time spent coding. Without a command, runs the daemon.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// Path of the config file
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Directory the log and tag files are kept in
+    #[arg(long, global = true, value_name = "PATH")]
+    data_dir: Option<PathBuf>,
+    /// Path of the socket editors connect to
+    #[arg(long, global = true, value_name = "PATH")]
+    socket: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Record sessions sent by editors
+    Daemon,
+    /// Print the time spent per language, project, file and branch
+    Report {
+        #[arg(value_enum, conflicts_with_all = ["since", "until"])]
+        period: Option<ReportPeriod>,
+        /// First day to include, as YYYY-MM-DD
+        #[arg(long)]
+        since: Option<NaiveDate>,
+        /// Last day to include, as YYYY-MM-DD
+        #[arg(long)]
+        until: Option<NaiveDate>,
+    },
+    /// Write every session to stdout
+    Export {
+        /// csv or jsonl
+        #[arg(long, default_value = "csv")]
+        format: Format,
+    },
+    /// Show what the running daemon is recording
+    Status,
+    /// List the names stored in a tag file
+    Tags {
+        #[arg(value_enum)]
+        kind: TagKind,
+    },
+    /// Work with the config file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Look for problems that stop sessions from being recorded
+    Doctor,
+    /// Upgrade a log to the current format
+    Migrate {
+        /// Defaults to the log in the data directory
+        path: Option<PathBuf>,
+    },
+    /// List projects, or fix up the ones recorded without an identity
+    Projects {
+        #[command(subcommand)]
+        command: Option<ProjectsCommand>,
+    },
+    /// Apply the project aliases in the config to older sessions
+    Rewrite {
+        /// Only print what would change
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ReportPeriod {
+    Today,
+    Week,
+    Month,
+    All,
+}
+
+impl From<ReportPeriod> for Range {
+    fn from(period: ReportPeriod) -> Self {
+        Range::from(match period {
+            ReportPeriod::Today => Period::Today,
+            ReportPeriod::Week => Period::Week,
+            ReportPeriod::Month => Period::Month,
+            ReportPeriod::All => Period::All,
+        })
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum TagKind {
+    Languages,
+    Projects,
+    Files,
+    Branches,
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Check the config and print the effective settings
+    Check,
+}
+
+#[derive(Subcommand)]
+enum ProjectsCommand {
+    /// List every project with its identity
+    List,
+    /// Give a project recorded without an identity one
+    Map {
+        name: String,
+        /// Root path or remote URL of the project
+        identity: String,
+    },
+    /// Move the sessions of a project without an identity to another one
+    Split {
+        name: String,
+        /// Root path or remote URL of the project to move the sessions to
+        identity: String,
+        /// First day to move, as YYYY-MM-DD
+        #[arg(long)]
+        since: Option<NaiveDate>,
+        /// Last day to move, as YYYY-MM-DD
+        #[arg(long)]
+        until: Option<NaiveDate>,
+    },
+}
+
+fn main() -> ExitCode {
+    // Commands print their own errors, so only the exit code is left.
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(()) => ExitCode::FAILURE,
+    }
+}
+
+fn run() -> Result<(), ()> {
     tracing_subscriber::registry()
         .with(fmt::layer())
         .with(EnvFilter::from_default_env())
         .init();
 
-    let executor = smol::LocalExecutor::new();
+    let cli = Cli::parse();
+
+    // Reading the config already starts the blocking thread pool of smol.
+    let socket = matches!(cli.command, None | Some(Command::Daemon))
+        .then(activated_socket)
+        .flatten();
+
+    paths::set_flags(Paths {
+        config: cli.config,
+        data_dir: cli.data_dir,
+        socket: cli.socket,
+    });
+
+    // The doctor reports an invalid config instead of stopping at it.
+    if let Some(Command::Doctor) = cli.command {
+        return doctor();
+    }
+
+    let config = read_config()?;
+    paths::set_configured(&config);
+
+    match cli.command.unwrap_or(Command::Daemon) {
+        Command::Daemon => daemon(config, socket),
+        Command::Report {
+            period,
+            since,
+            until,
+        } => {
+            let range = match period {
+                Some(period) => period.into(),
+                None if since.is_some() || until.is_some() => Range::Custom { since, until },
+                None => Range::Today,
+            };
+            report(range, &config)
+        }
+        Command::Export { format } => export(format),
+        Command::Status => status(),
+        Command::Tags { kind } => tags(kind),
+        Command::Config {
+            command: ConfigCommand::Check,
+        } => check_config(&config),
+        Command::Doctor => unreachable!("doctor is run before the config is read"),
+        Command::Migrate { path } => migrate(path),
+        Command::Projects { command } => match command.unwrap_or(ProjectsCommand::List) {
+            ProjectsCommand::List => list_projects(),
+            ProjectsCommand::Map { name, identity } => map_project(name, identity),
+            ProjectsCommand::Split {
+                name,
+                identity,
+                since,
+                until,
+            } => split_project(name, identity, Range::Custom { since, until }),
+        },
+        Command::Rewrite { dry_run } => rewrite(dry_run, &config),
+    }
+}
 
-    smol::block_on(executor.run(async {
-        let data_dir = {
-            let mut dir = data_dir().expect("Failed to find data directory");
-            dir.push("code-statistics");
-            dir
-        };
-        create_dir_all(&data_dir)
-            .await
-            .expect("Failed to create data directory");
+/// Reads the config, printing any warnings about it.
+fn read_config() -> Result<Config, ()> {
+    let path = config_path();
+    let loaded = smol::block_on(load_config())
+        .map_err(|err| eprintln!("Invalid config at {}:\n{err}", path.display()))?;
 
-        let config: &'static Config = Box::leak(Box::new(read_config().await));
+    for warning in loaded.warnings {
+        eprintln!("Warning: {warning} in {}", path.display());
+    }
 
-        debug!(?config);
+    Ok(loaded.config)
+}
+
+fn check_config(config: &Config) -> Result<(), ()> {
+    let config =
+        toml::to_string_pretty(config).map_err(|err| eprintln!("Failed to print config: {err}"))?;
+
+    eprintln!(
+        "{} is valid, the effective config is:",
+        config_path().display()
+    );
+    print!("{config}");
+    Ok(())
+}
+
+fn doctor() -> Result<(), ()> {
+    let findings = smol::block_on(doctor::diagnose());
+
+    for finding in &findings {
+        println!("{finding}");
+    }
+
+    if findings
+        .iter()
+        .any(|finding| finding.level == doctor::Level::Error)
+    {
+        Err(())
+    } else {
+        Ok(())
+    }
+}
+
+fn report(range: Range, config: &Config) -> Result<(), ()> {
+    if !log_path().exists() {
+        println!("Nothing has been recorded yet");
+        return Ok(());
+    }
+
+    let (start, end) = range.bounds(Local::now());
+
+    let sessions = read_sessions()?.into_iter().map(|session| Session {
+        language: config.languages.resolve(&session.language).to_owned(),
+        ..session
+    });
+
+    let mut report = Report::new(sessions, start, end);
+    report.group(&config.languages);
+    report
+        .print(std::io::stdout().lock())
+        .map_err(|err| eprintln!("Failed to print report: {err}"))
+}
+
+fn read_sessions() -> Result<Vec<Session>, ()> {
+    smol::block_on(reader::read_sessions())
+        .map_err(|err| eprintln!("Failed to read log file: {err}"))
+}
+
+fn export(format: Format) -> Result<(), ()> {
+    code_statistics::export::export(read_sessions()?, format, std::io::stdout().lock())
+        .map_err(|err| eprintln!("Failed to export sessions: {err}"))
+}
+
+fn status() -> Result<(), ()> {
+    let path = find_socket_path()
+        .ok_or_else(|| eprintln!("XDG_RUNTIME_DIR is not set, the socket path has to be given"))?;
+
+    let response = smol::block_on(async {
+        let (mut client, _) = Client::connect(&path).await?;
+        client.request(&Request::Status).await
+    })
+    .map_err(|err| eprintln!("Failed to get the status from {}: {err}", path.display()))?;
+
+    let Response::Status {
+        active: true,
+        language,
+        project,
+        branch,
+        since,
+    } = response
+    else {
+        println!("Not recording");
+        return Ok(());
+    };
+
+    let mut line = format!(
+        "Recording {} in {}",
+        language.unwrap_or_default(),
+        project.unwrap_or_default()
+    );
+    if let Some(branch) = branch {
+        line.push_str(&format!(" on {branch}"));
+    }
+    if let Some(since) = since.and_then(|since| DateTime::from_timestamp(since, 0)) {
+        line.push_str(&format!(
+            " since {} ({})",
+            since.with_timezone(&Local).format("%H:%M"),
+            format_duration(Utc::now() - since)
+        ));
+    }
+    println!("{line}");
+
+    Ok(())
+}
+
+/// Prints the index and name of every tag, without creating the tag file if
+/// nothing has been recorded yet.
+fn tags(kind: TagKind) -> Result<(), ()> {
+    let name = match kind {
+        TagKind::Languages => "languages",
+        TagKind::Projects => "projects",
+        TagKind::Files => "files",
+        TagKind::Branches => "branches",
+    };
+
+    let path = tags_path(name);
+    let tags = match std::fs::read_to_string(&path) {
+        Ok(tags) => tags,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => {
+            eprintln!("Failed to read {}: {err}", path.display());
+            return Err(());
+        }
+    };
+
+    for (idx, tag) in tags.lines().enumerate() {
+        println!("{idx}\t{tag}");
+    }
+
+    Ok(())
+}
+
+fn migrate(path: Option<PathBuf>) -> Result<(), ()> {
+    let path = path.unwrap_or_else(log_path);
+
+    let header = File::open(&path)
+        .map_err(Into::into)
+        .and_then(|mut file| Header::read(&mut file))
+        .map_err(|err| eprintln!("Failed to read {}: {err}", path.display()))?;
+
+    if header.version == format::VERSION {
+        println!(
+            "{} is already at version {}",
+            path.display(),
+            format::VERSION
+        );
+        return Ok(());
+    }
+
+    let backup_path = format::migrate(&path, &header)
+        .map_err(|err| eprintln!("Failed to migrate {}: {err}", path.display()))?;
+
+    println!(
+        "Migrated {} from version {} to {}, the original was kept at {}",
+        path.display(),
+        header.version,
+        format::VERSION,
+        backup_path.display()
+    );
+
+    Ok(())
+}
+
+/// Reads the projects without creating anything, for commands that only look
+/// them up.
+fn load_projects() -> Result<Projects, ()> {
+    smol::block_on(Projects::load()).map_err(|err| eprintln!("Failed to read projects: {err}"))
+}
+
+/// Opens the projects for commands that add to them.
+fn open_projects() -> Result<Projects, ()> {
+    smol::block_on(Projects::new()).map_err(|err| eprintln!("Failed to open projects: {err}"))
+}
+
+fn list_projects() -> Result<(), ()> {
+    let projects = load_projects()?;
+
+    for (idx, (project, name)) in projects.all().into_iter().zip(projects.names()).enumerate() {
+        match project.identity {
+            Some(identity) => println!("{idx}\t{name}\t{identity}"),
+            None => println!("{idx}\t{name}\t(no identity)"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Finds the project called `name` that was recorded without an identity.
+fn legacy_project(projects: &[Project], name: &str) -> Result<usize, ()> {
+    projects
+        .iter()
+        .position(|project| project.identity.is_none() && project.name == name)
+        .ok_or_else(|| eprintln!("There is no project called {name} without an identity"))
+}
+
+fn parse_identity(value: &str) -> Result<String, ()> {
+    normalize_identity(value).ok_or_else(|| eprintln!("Expected a root path or remote URL"))
+}
+
+/// Takes the lock the daemon holds while it runs, so that the log and tags
+/// can be changed. Without a data directory nothing has been recorded, so
+/// there is nothing to lock either.
+fn lock_data() -> Result<Option<DataLock>, ()> {
+    match lock::try_lock() {
+        Ok(Some(lock)) => Ok(Some(lock)),
+        Ok(None) => {
+            eprintln!("The daemon is running, stop it before changing the log");
+            Err(())
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => {
+            eprintln!("Failed to lock {}: {err}", lock_path().display());
+            Err(())
+        }
+    }
+}
+
+/// Moves sessions between projects by rewriting the log, keeping the
+/// original as `log.<unix time>.bak`, or `log.<unix time>.<n>.bak` when that
+/// is taken. `map` gets the project and start time of each session and
+/// returns the project it belongs to.
+fn rewrite_projects(mut map: impl FnMut(usize, i64) -> usize) -> Result<(), ()> {
+    let path = log_path();
+    let time = Utc::now().timestamp();
+    let backup_path = (0..)
+        .map(|n| match n {
+            0 => path.with_extension(format!("{time}.bak")),
+            n => path.with_extension(format!("{time}.{n}.bak")),
+        })
+        .find(|backup_path| !backup_path.exists())
+        .expect("Ran out of backup names");
+
+    let header =
+        format::prepare(&path).map_err(|err| eprintln!("Failed to read log file: {err}"))?;
+
+    format::rewrite(&path, &header, &backup_path, |record| match record {
+        Record::Start {
+            language,
+            project,
+            file,
+            branch,
+            time,
+        } => Record::Start {
+            language,
+            project: map(project, time),
+            file,
+            branch,
+            time,
+        },
+        Record::End { time } => Record::End { time },
+    })
+    .map_err(|err| eprintln!("Failed to rewrite log file: {err}"))?;
+
+    println!("The original log was kept at {}", backup_path.display());
+
+    Ok(())
+}
+
+fn map_project(name: String, identity: String) -> Result<(), ()> {
+    let identity = parse_identity(&identity)?;
+    let _lock = lock_data()?;
+
+    let mut projects = load_projects()?.all();
+    let from = legacy_project(&projects, &name)?;
+
+    if let Some(to) = projects
+        .iter()
+        .position(|project| project.identity.as_ref() == Some(&identity))
+    {
+        rewrite_projects(|project, _| if project == from { to } else { project })?;
+        println!("Moved the sessions of {name} to the project at {identity}");
+    } else {
+        projects[from].identity = Some(identity.clone());
+        let tags = projects.iter().map(Project::to_tag).collect::<Vec<_>>();
+        write_tags("projects", &tags)
+            .map_err(|err| eprintln!("Failed to write projects: {err}"))?;
+        println!("Mapped {name} to the project at {identity}");
+    }
+
+    Ok(())
+}
 
-        let (log, log_system_channel) = log(&executor, config);
+fn split_project(name: String, identity: String, range: Range) -> Result<(), ()> {
+    let identity = parse_identity(&identity)?;
+    let _lock = lock_data()?;
 
-        let languages = Rc::new(Tags::new("languages").await);
-        let projects = Rc::new(Tags::new("projects").await);
+    let (start, end) = range.bounds(Local::now());
+    let (start, end) = (start.timestamp(), end.timestamp());
 
-        let fd = unblock(|| {
-            let num_descriptors = unsafe { sd_listen_fds(1) };
-            if num_descriptors <= 0 {
-                warn!("Failed to get sockets from systemd");
-                return None;
+    let from = legacy_project(&load_projects()?.all(), &name)?;
+    let projects = open_projects()?;
+    let to = smol::block_on(projects.get(&Project {
+        name: name.clone(),
+        identity: Some(identity.clone()),
+    }));
+
+    rewrite_projects(|project, time| {
+        if project == from && (start..end).contains(&time) {
+            to
+        } else {
+            project
+        }
+    })?;
+    println!("Moved the matching sessions of {name} to the project at {identity}");
+
+    Ok(())
+}
+
+/// The projects that sessions in the log belong to.
+fn recorded_projects() -> Result<HashSet<usize>, ()> {
+    let path = log_path();
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(err) => {
+            eprintln!("Failed to read log file: {err}");
+            return Err(());
+        }
+    };
+
+    let sessions = RawReader::new(BufReader::new(file))
+        .map_err(|err| eprintln!("Failed to read log file: {err}"))?;
+
+    let mut projects = HashSet::new();
+    for session in sessions {
+        match session {
+            Ok(session) => {
+                projects.insert(session.project);
             }
-            if unsafe { sd_is_socket_unix(SD_LISTEN_FDS_START, 1, 1, ptr::null(), 0) } != 1 {
-                warn!("Wrong kind of socket");
-                return None;
+            // The incomplete record is dropped when the log is rewritten.
+            Err(reader::Error::Format(format::Error::Truncated)) => break,
+            Err(err) => {
+                eprintln!("Failed to read log file: {err}");
+                return Err(());
             }
-            Some(unsafe { OwnedFd::from_raw_fd(SD_LISTEN_FDS_START) })
-        })
-        .await;
-        let socket = if let Some(fd) = fd {
-            UnixListener::try_from(fd).expect("Failed to bind to ipc socket")
+        }
+    }
+
+    Ok(projects)
+}
+
+/// Applies the project aliases in the config to the sessions that were
+/// recorded before they were added.
+fn rewrite(dry_run: bool, config: &Config) -> Result<(), ()> {
+    let _lock = if dry_run { None } else { lock_data()? };
+
+    let projects = load_projects()?;
+    let recorded = recorded_projects()?;
+
+    // Projects that were already rewritten stay in the tag file and still
+    // match their alias, so only the ones the log refers to count.
+    let mut renames = Vec::new();
+    for (idx, (project, name)) in projects.all().into_iter().zip(projects.names()).enumerate() {
+        if !recorded.contains(&idx) {
+            continue;
+        }
+
+        let resolved = config.projects.resolve(project.clone());
+        if resolved == project || projects.position(&resolved) == Some(idx) {
+            continue;
+        }
+
+        println!("{name} -> {}", resolved.name);
+        renames.push((idx, resolved));
+    }
+
+    if renames.is_empty() {
+        println!("No projects need to be rewritten");
+        return Ok(());
+    }
+
+    if dry_run {
+        return Ok(());
+    }
+
+    let projects = open_projects()?;
+    let renames = renames
+        .into_iter()
+        .map(|(idx, resolved)| (idx, smol::block_on(projects.get(&resolved))))
+        .collect::<HashMap<_, _>>();
+
+    rewrite_projects(|project, _| renames.get(&project).copied().unwrap_or(project))
+}
+
+/// Takes the socket passed by systemd, if the daemon was socket activated.
+/// This unsets the environment variables describing it, so it has to run
+/// before any other threads are started.
+fn activated_socket() -> Option<OwnedFd> {
+    let fds = match activation::listen_fds() {
+        Ok(fds) if !fds.is_empty() => fds,
+        Ok(_) => {
+            warn!("Failed to get sockets from systemd");
+            return None;
+        }
+        Err(err) => {
+            warn!(%err, "Failed to get sockets from systemd");
+            return None;
+        }
+    };
+
+    // Passed file descriptors stay open until the process exits.
+    let fd = unsafe { BorrowedFd::borrow_raw(fds[0].fd) };
+    if !activation::is_listening_unix_stream(fd).unwrap_or(false) {
+        warn!("Wrong kind of socket");
+        return None;
+    }
+
+    Some(unsafe { OwnedFd::from_raw_fd(fds[0].fd) })
+}
+
+fn daemon(config: Config, fd: Option<OwnedFd>) -> Result<(), ()> {
+    let config = SharedConfig::new(config);
+
+    fs::create_dir_all(data_dir()).expect("Failed to create data directory");
+
+    // Held until the daemon exits, so the log isn't rewritten underneath it.
+    let Some(_lock) = lock::try_lock().expect("Failed to lock data directory") else {
+        error!(
+            "Another daemon is already running with {}",
+            data_dir().display()
+        );
+        return Err(());
+    };
+
+    let executor = smol::LocalExecutor::new();
+
+    let log_result = smol::block_on(executor.run(async {
+        debug!(?config);
+
+        executor
+            .spawn(
+                reload_on_hangup(config.clone())
+                    .instrument(span!(Level::DEBUG, "config reload task")),
+            )
+            .detach();
+
+        let (log, log_system_channel, log_status, log_task) = log(&executor, config.clone());
+        let stop_system_channel = log_system_channel.clone();
+
+        let languages = Rc::new(
+            Tags::new("languages")
+                .await
+                .expect("Failed to open tag file"),
+        );
+        let projects = Rc::new(Projects::new().await.expect("Failed to open tag file"));
+        let files = Rc::new(Tags::new("files").await.expect("Failed to open tag file"));
+        let branches = Rc::new(
+            Tags::new("branches")
+                .await
+                .expect("Failed to open tag file"),
+        );
+
+        let status = Arc::new(Mutex::new(CurrentStatus::default()));
+
+        executor
+            .spawn(
+                service::serve(
+                    log_status,
+                    status.clone(),
+                    languages.clone(),
+                    projects.clone(),
+                    branches.clone(),
+                )
+                .instrument(span!(Level::DEBUG, "dbus service task")),
+            )
+            .detach();
+
+        let (socket, bound_path) = if let Some(fd) = fd {
+            (
+                UnixListener::try_from(fd).expect("Failed to bind to ipc socket"),
+                None,
+            )
         } else {
             warn!("Falling back to non systemd socket");
-            UnixListener::bind("/run/user/1000/code-statistics")
-                .expect("Failed to bind to ipc socket")
+            let path = socket_path();
+            (
+                UnixListener::bind(&path).expect("Failed to bind to ipc socket"),
+                Some(path),
+            )
         };
         let mut listener = socket.incoming();
 
         let ids = Rc::new(Cell::new(0));
 
         executor
-            .spawn(
-                async move {
-                    let connection = Connection::system()
-                        .await
-                        .expect("Failed to connect to dbus");
-                    let proxy = ManagerProxy::new(&connection)
-                        .await
-                        .expect("Failed to connect to logind");
-
-                    let mut stream = proxy
-                        .receive_prepare_for_sleep()
-                        .await
-                        .expect("Failed to get sleep signals");
-
-                    let mut inhibit_fd = Some(
-                        proxy
-                            .inhibit(
-                                "sleep",
-                                "code-statistics",
-                                "Logging end of activity",
-                                "delay",
-                            )
-                            .await
-                            .expect("Failed to inhibit sleep"),
-                    );
-
-                    trace!("finished setting up");
-
-                    while let Some(is_sleeping) = stream.next().await {
-                        let args = is_sleeping
-                            .args()
-                            .expect("Failed to parse prepare for sleep args");
-
-                        trace!(name = "received sleep signal", ?args);
-
-                        if !args.start {
-                            log_system_channel.send(code_statistics::log::SystemMessage::Resume);
-
-                            inhibit_fd = Some(
-                                proxy
-                                    .inhibit(
-                                        "sleep",
-                                        "code-statistics",
-                                        "Logging end of activity",
-                                        "delay",
-                                    )
-                                    .await
-                                    .expect("Failed to inhibit sleep"),
-                            );
-                        } else {
-                            log_system_channel.send(code_statistics::log::SystemMessage::Suspend {
-                                time: Utc::now(),
-                            });
-
-                            if let Some(fd) = inhibit_fd.take() {
-                                drop(fd);
-                            }
-                        }
-                    }
-                }
-                .instrument(span!(Level::DEBUG, "suspend task")),
-            )
+            .spawn(logind::watch(log_system_channel).instrument(span!(Level::DEBUG, "logind task")))
             .detach();
 
-        while let Some(stream) = listener.next().await {
-            let stream = stream.expect("Failed to get next connection");
-            let mut buffered_stream = BufReader::new(stream);
-
-            let log = log.clone();
-            let languages = languages.clone();
-            let projects = projects.clone();
-
-            let id = ids.get();
-            ids.set(id + 1);
-
-            executor
-                .spawn(
-                    async move {
-                        let mut active = false;
-
-                        info!("joined");
-
-                        loop {
-                            let Ok(line): Result<String, std::io::Error> = (
-                                async {
-                                    let mut line = String::new();
-                                    let amount_read = buffered_stream.read_line(&mut line).await?;
-                                    trace!("sent data");
-                                    if amount_read == 0 {
-                                        Err(std::io::Error::new(
-                                            ErrorKind::NotConnected,
-                                            "Client no longer connected",
-                                        ))
-                                    } else {
-                                        Ok(line)
-                                    }
-                                },
-                                async {
-                                    if active {
-                                        poll_fn(|_| Poll::<()>::Pending).await;
-                                    } else {
-                                        Timer::after(config.timeout).await;
-                                    }
-                                    trace!("timeout reached");
-                                    Ok("".to_string())
-                                },
-                            )
-                                .race()
-                                .await
-                            else {
-                                debug!("disconnected");
-                                log.send(LogMessage::End {
-                                    id,
-                                    time: Utc::now(),
-                                });
-                                break;
-                            };
-
-                            if line.trim().is_empty() {
-                                debug!("received end message");
-                                log.send(LogMessage::End {
-                                    id,
-                                    time: Utc::now(),
-                                });
-                                active = false;
-                            } else {
-                                let line = line.trim();
-
-                                let (language, project) =
-                                    line.split_once(30u8 as char).unwrap_or((line, "unknown"));
-
-                                debug!(event = "received start", language, project);
-
-                                if config.ignored_languages.contains(language) {
-                                    trace!("skipping ignored language");
-                                    continue;
-                                }
-
-                                if language.is_empty() {
-                                    trace!("ignoring empty language");
-                                    continue;
-                                }
-
-                                let language = languages.get(language).await;
-                                let project = projects.get(project).await;
-
-                                active = true;
-
-                                log.send(LogMessage::Start {
-                                    id,
-                                    time: Utc::now(),
-                                    language,
-                                    project,
-                                });
-                            }
-                        }
-                    }
-                    .instrument(span!(Level::DEBUG, "client", id)),
-                )
-                .detach();
+        let shared = Rc::new(Shared {
+            log,
+            languages,
+            projects,
+            files,
+            branches,
+            config,
+            status,
+        });
+
+        let accept = async {
+            while let Some(stream) = listener.next().await {
+                let stream = stream.expect("Failed to get next connection");
+
+                let id = ids.get();
+                ids.set(id + 1);
+
+                executor
+                    .spawn(
+                        connection::handle(stream, id, shared.clone()).instrument(span!(
+                            Level::DEBUG,
+                            "client",
+                            id
+                        )),
+                    )
+                    .detach();
+            }
+        };
+
+        if let Err(err) = notify(notify::READY) {
+            warn!(%err, "Failed to notify systemd");
+        }
+
+        // The log task only finishes on its own when it failed, and there is
+        // no point in accepting sessions that can't be recorded.
+        let mut log_task = AssertUnwindSafe(log_task).catch_unwind();
+        let failed = (
+            async {
+                (accept, stop_requested()).race().await;
+                None
+            },
+            async { Some((&mut log_task).await) },
+        )
+            .race()
+            .await;
+
+        if let Err(err) = notify(notify::STOPPING) {
+            warn!(%err, "Failed to notify systemd");
         }
+
+        let log_result = match failed {
+            Some(result) => result,
+            None => {
+                stop_system_channel.send(SystemMessage::Stop { time: Utc::now() });
+                log_task.await
+            }
+        };
+
+        // A socket passed by systemd is left to it, but one bound here would
+        // keep the next start from binding again.
+        if let Some(path) = bound_path {
+            if let Err(err) = std::fs::remove_file(&path) {
+                warn!(%err, "Failed to remove the socket");
+            }
+        }
+
+        log_result
     }));
 
+    if log_result.is_err() {
+        error!("The log task failed, stopping");
+        return Err(());
+    }
+
     Ok(())
 }
+
+/// Waits for `SIGTERM` or `SIGINT`.
+async fn stop_requested() {
+    let mut signals = match Signals::new([Signal::Term, Signal::Int]) {
+        Ok(signals) => signals,
+        Err(err) => {
+            warn!(%err, "Failed to listen for SIGTERM, the session won't be closed when stopping");
+            return pending().await;
+        }
+    };
+
+    while let Some(signal) = signals.next().await {
+        match signal {
+            Ok(signal) => {
+                info!(?signal, "stopping");
+                return;
+            }
+            Err(err) => warn!(%err, "Failed to receive signal"),
+        }
+    }
+}
@@ -0,0 +1,71 @@
+//! Status notifications to the service manager, see sd_notify(3).
+//!
+//! Like [`crate::activation`], this goes through libsystemd with the
+//! `libsystemd` feature and otherwise writes to `NOTIFY_SOCKET` directly.
+use std::io;
+
+/// The daemon finished starting up and is accepting connections.
+pub const READY: &str = "READY=1";
+/// The daemon is shutting down.
+pub const STOPPING: &str = "STOPPING=1";
+
+/// Sends `state` to the service manager. Returns `false` when the daemon
+/// wasn't started by one that listens for notifications.
+pub fn notify(state: &str) -> io::Result<bool> {
+    imp::notify(state)
+}
+
+#[cfg(feature = "libsystemd")]
+mod imp {
+    use core::ffi::{c_char, c_int};
+    use std::{ffi::CString, io};
+
+    #[link(name = "systemd")]
+    extern "C" {
+        /// Notifies the service manager of start-up completion, status
+        /// changes and other events. Returns a negative errno style error
+        /// code on failure, 0 if $NOTIFY_SOCKET was not set and a positive
+        /// value if the notification was sent.
+        ///
+        /// See sd_notify(3) for more information.
+        fn sd_notify(unset_environment: c_int, state: *const c_char) -> c_int;
+    }
+
+    pub fn notify(state: &str) -> io::Result<bool> {
+        let state = CString::new(state).map_err(io::Error::other)?;
+
+        match unsafe { sd_notify(0, state.as_ptr()) } {
+            result if result < 0 => Err(io::Error::from_raw_os_error(-result)),
+            result => Ok(result > 0),
+        }
+    }
+}
+
+#[cfg(not(feature = "libsystemd"))]
+mod imp {
+    use std::{
+        env, io,
+        os::{
+            linux::net::SocketAddrExt,
+            unix::{
+                ffi::OsStrExt,
+                net::{SocketAddr, UnixDatagram},
+            },
+        },
+    };
+
+    pub fn notify(state: &str) -> io::Result<bool> {
+        let Some(path) = env::var_os("NOTIFY_SOCKET").filter(|path| !path.is_empty()) else {
+            return Ok(false);
+        };
+
+        // A leading @ stands for a socket in the abstract namespace.
+        let address = match path.as_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(&path)?,
+        };
+
+        UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &address)?;
+        Ok(true)
+    }
+}
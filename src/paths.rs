@@ -0,0 +1,101 @@
+//! Where the daemon keeps its files.
+//!
+//! Each path can be given as a command line flag, an environment variable or,
+//! except for the path of the config itself, in the config. Flags take
+//! priority over the environment, which takes priority over the config.
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+use dirs::{config_dir, home_dir, runtime_dir};
+use parking_lot::Mutex;
+
+use crate::config::Config;
+
+pub const CONFIG_ENV: &str = "CODE_STATISTICS_CONFIG";
+pub const DATA_DIR_ENV: &str = "CODE_STATISTICS_DATA_DIR";
+pub const SOCKET_ENV: &str = "CODE_STATISTICS_SOCKET";
+
+/// Paths that were set explicitly, with `None` for the ones left at their
+/// default.
+#[derive(Debug, Default, Clone)]
+pub struct Paths {
+    pub config: Option<PathBuf>,
+    pub data_dir: Option<PathBuf>,
+    pub socket: Option<PathBuf>,
+}
+
+impl Paths {
+    const EMPTY: Paths = Paths {
+        config: None,
+        data_dir: None,
+        socket: None,
+    };
+}
+
+static FLAGS: Mutex<Paths> = Mutex::new(Paths::EMPTY);
+static CONFIGURED: Mutex<Paths> = Mutex::new(Paths::EMPTY);
+
+/// Replaces a leading `~` with the home directory.
+pub fn expand_home(path: &str) -> Option<PathBuf> {
+    match path.strip_prefix('~') {
+        Some(rest) => Some(home_dir()?.join(rest.trim_start_matches('/'))),
+        None => Some(PathBuf::from(path)),
+    }
+}
+
+/// Sets the paths given on the command line.
+pub fn set_flags(paths: Paths) {
+    *FLAGS.lock() = paths;
+}
+
+/// Sets the paths given in `config`. These are only read when the daemon
+/// starts, so reloading the config doesn't move any files.
+pub fn set_configured(config: &Config) {
+    *CONFIGURED.lock() = Paths {
+        config: None,
+        data_dir: config.data_dir.as_deref().and_then(expand_home),
+        socket: config.socket.as_deref().and_then(expand_home),
+    };
+}
+
+fn resolve(pick: impl Fn(&Paths) -> Option<PathBuf>, env: &str) -> Option<PathBuf> {
+    pick(&FLAGS.lock())
+        .or_else(|| {
+            env::var_os(env)
+                .filter(|value| !value.is_empty())
+                .map(PathBuf::from)
+        })
+        .or_else(|| pick(&CONFIGURED.lock()))
+}
+
+/// Defaults to `~/.config/code-statistics/config.toml`.
+pub fn config_path() -> PathBuf {
+    resolve(|paths| paths.config.clone(), CONFIG_ENV).unwrap_or_else(|| {
+        config_dir()
+            .expect("Failed to get config dir")
+            .join(Path::new("code-statistics/config.toml"))
+    })
+}
+
+/// Defaults to `~/.local/share/code-statistics`.
+pub fn data_dir() -> PathBuf {
+    resolve(|paths| paths.data_dir.clone(), DATA_DIR_ENV).unwrap_or_else(|| {
+        let mut dir = dirs::data_dir().expect("Failed to find data directory");
+        dir.push("code-statistics");
+        dir
+    })
+}
+
+/// Defaults to `$XDG_RUNTIME_DIR/code-statistics`, `None` when that isn't set
+/// and no path was given.
+pub fn find_socket_path() -> Option<PathBuf> {
+    resolve(|paths| paths.socket.clone(), SOCKET_ENV)
+        .or_else(|| Some(runtime_dir()?.join("code-statistics")))
+}
+
+/// See [`find_socket_path`].
+pub fn socket_path() -> PathBuf {
+    find_socket_path().expect("XDG_RUNTIME_DIR is not set, the socket path has to be given")
+}
@@ -0,0 +1,73 @@
+use std::fmt::{self, Debug, Display};
+
+use globset::{GlobBuilder, GlobMatcher};
+use regex::Regex;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Prefix that marks a pattern in the config as a regular expression instead
+/// of a glob.
+pub const REGEX_PREFIX: &str = "re:";
+
+/// A glob or regular expression from the config.
+///
+/// Patterns are written as strings, with globs matching the whole string and
+/// `*` not matching `/`, so `/tmp/**` matches everything under `/tmp`.
+/// Regular expressions are prefixed with `re:` and match anywhere in the
+/// string unless they are anchored.
+#[derive(Clone)]
+pub enum Pattern {
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Result<Self, String> {
+        if let Some(regex) = pattern.strip_prefix(REGEX_PREFIX) {
+            Regex::new(regex)
+                .map(Pattern::Regex)
+                .map_err(|err| format!("invalid regex {regex:?}: {err}"))
+        } else {
+            GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()
+                .map(|glob| Pattern::Glob(glob.compile_matcher()))
+                .map_err(|err| format!("invalid glob {pattern:?}: {err}"))
+        }
+    }
+
+    pub fn is_match(&self, value: &str) -> bool {
+        match self {
+            Pattern::Glob(glob) => glob.is_match(value),
+            Pattern::Regex(regex) => regex.is_match(value),
+        }
+    }
+}
+
+/// Formats the pattern the way it is written in the config.
+impl Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pattern::Glob(glob) => write!(f, "{}", glob.glob().glob()),
+            Pattern::Regex(regex) => write!(f, "{REGEX_PREFIX}{regex}"),
+        }
+    }
+}
+
+impl Debug for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        ser.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(de)?;
+        Pattern::new(&pattern).map_err(de::Error::custom)
+    }
+}
@@ -0,0 +1,241 @@
+//! Messages exchanged with clients over the unix socket.
+//!
+//! Clients speaking the structured protocol send one JSON object per line,
+//! starting with a `hello` message that carries the client name and the
+//! protocol version it speaks, and read one JSON object per line back. Besides
+//! reporting activity they can query the current session and totals. Lines
+//! that don't start with `{` are read in the legacy format: either
+//! `language\x1Eproject` to start a session or an empty line to end it.
+use std::fmt::{self, Display};
+
+use serde::{Deserialize, Serialize};
+
+use crate::report::Range;
+
+/// The newest version of the structured protocol.
+pub const VERSION: u32 = 1;
+
+/// Separates the language from the project in the legacy format.
+pub const LEGACY_SEPARATOR: char = '\x1e';
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    Hello {
+        client: String,
+        version: u32,
+    },
+    Start {
+        language: String,
+        project: String,
+        /// Path of the file being edited, relative to the project root.
+        #[serde(default)]
+        file: Option<String>,
+        /// Absolute path of the project root, which tells apart projects
+        /// with the same name.
+        #[serde(default)]
+        root: Option<String>,
+        /// URL of the remote the project was cloned from, which is preferred
+        /// over `root` so that every clone counts as the same project.
+        #[serde(default)]
+        remote: Option<String>,
+        /// Git branch checked out in the project.
+        #[serde(default)]
+        branch: Option<String>,
+    },
+    End,
+    /// Keeps the current session going without repeating what it is.
+    Heartbeat,
+    /// Asks for the session that is currently being recorded.
+    Status,
+    /// Asks for the time spent in `period`, optionally only counting one
+    /// project or language.
+    Total {
+        #[serde(default)]
+        project: Option<String>,
+        /// Root path and remote URL of the project, like in `Start`. When
+        /// given, the project is matched by them instead of by name.
+        #[serde(default)]
+        root: Option<String>,
+        #[serde(default)]
+        remote: Option<String>,
+        #[serde(default)]
+        language: Option<String>,
+        #[serde(default)]
+        period: Period,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Period {
+    #[default]
+    Today,
+    Week,
+    Month,
+    All,
+}
+
+impl From<Period> for Range {
+    fn from(period: Period) -> Self {
+        match period {
+            Period::Today => Range::Today,
+            Period::Week => Range::Week,
+            Period::Month => Range::Month,
+            Period::All => Range::Custom {
+                since: None,
+                until: None,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Hello {
+        version: u32,
+    },
+    Error {
+        message: String,
+    },
+    /// The session being recorded, with `since` as a unix timestamp.
+    Status {
+        active: bool,
+        language: Option<String>,
+        project: Option<String>,
+        branch: Option<String>,
+        since: Option<i64>,
+    },
+    Total {
+        seconds: i64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Line {
+    Legacy(Request),
+    Structured(Request),
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Json(serde_json::Error),
+    InvalidLegacy(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Json(err) => write!(f, "invalid message: {err}"),
+            Error::InvalidLegacy(line) => write!(f, "invalid legacy message {line:?}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Json(err) => Some(err),
+            Error::InvalidLegacy(_) => None,
+        }
+    }
+}
+
+fn parse_legacy(line: &str) -> Result<Request, Error> {
+    if line.is_empty() {
+        return Ok(Request::End);
+    }
+
+    let (language, project) = line
+        .split_once(LEGACY_SEPARATOR)
+        .unwrap_or((line, "unknown"));
+
+    if [language, project]
+        .iter()
+        .any(|field| field.chars().any(char::is_control))
+    {
+        return Err(Error::InvalidLegacy(line.to_owned()));
+    }
+
+    Ok(Request::Start {
+        language: language.to_owned(),
+        project: project.to_owned(),
+        file: None,
+        root: None,
+        remote: None,
+        branch: None,
+    })
+}
+
+/// Parses a line sent by a client, in either the structured or the legacy
+/// format.
+pub fn parse(line: &str) -> Result<Line, Error> {
+    let line = line.trim();
+
+    if line.starts_with('{') {
+        serde_json::from_str(line)
+            .map(Line::Structured)
+            .map_err(Error::Json)
+    } else {
+        parse_legacy(line).map(Line::Legacy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_structured_lines() {
+        assert_eq!(
+            parse(r#"{"type":"start","language":"rust","project":"api","branch":"main"}"#).unwrap(),
+            Line::Structured(Request::Start {
+                language: "rust".to_owned(),
+                project: "api".to_owned(),
+                file: None,
+                root: None,
+                remote: None,
+                branch: Some("main".to_owned()),
+            })
+        );
+        assert_eq!(
+            parse(r#"{"type":"total","period":"week"}"#).unwrap(),
+            Line::Structured(Request::Total {
+                project: None,
+                root: None,
+                remote: None,
+                language: None,
+                period: Period::Week,
+            })
+        );
+        assert!(matches!(
+            parse(r#"{"type":"unknown"}"#),
+            Err(Error::Json(_))
+        ));
+    }
+
+    #[test]
+    fn parses_legacy_lines() {
+        assert_eq!(
+            parse("rust\x1eapi\n").unwrap(),
+            Line::Legacy(Request::Start {
+                language: "rust".to_owned(),
+                project: "api".to_owned(),
+                file: None,
+                root: None,
+                remote: None,
+                branch: None,
+            })
+        );
+        assert!(matches!(
+            parse("rust").unwrap(),
+            Line::Legacy(Request::Start { project, .. }) if project == "unknown"
+        ));
+        assert_eq!(parse("\n").unwrap(), Line::Legacy(Request::End));
+        assert!(matches!(
+            parse("rust\x1eap\x07i"),
+            Err(Error::InvalidLegacy(_))
+        ));
+    }
+}
@@ -0,0 +1,365 @@
+use std::{
+    fmt::{self, Display},
+    fs::File,
+    io::{self, BufReader, ErrorKind, Read, Seek},
+    path::Path,
+};
+
+use chrono::{DateTime, Utc};
+use smol::unblock;
+
+use crate::{
+    format::{self, Header, Record},
+    log::log_path,
+    projects::Projects,
+    tags::Tags,
+};
+
+/// A session with its language and project still stored as indices into the
+/// tag files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawSession {
+    pub language: usize,
+    pub project: usize,
+    pub file: Option<usize>,
+    pub branch: Option<usize>,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+/// A session with its language and project resolved through [`Tags`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub language: String,
+    pub project: String,
+    /// Path of the file relative to the project root, if the editor sent one.
+    pub file: Option<String>,
+    /// Git branch the session was spent on, if the editor sent one.
+    pub branch: Option<String>,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+impl Session {
+    pub fn duration(&self) -> chrono::Duration {
+        self.end - self.start
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Format(format::Error),
+    InvalidTimestamp(i64),
+    UnknownLanguage(usize),
+    UnknownProject(usize),
+    UnknownFile(usize),
+    UnknownBranch(usize),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Format(err) => write!(f, "{err}"),
+            Error::InvalidTimestamp(timestamp) => write!(f, "invalid timestamp {timestamp}"),
+            Error::UnknownLanguage(idx) => write!(f, "language {idx} is not in the tag file"),
+            Error::UnknownProject(idx) => write!(f, "project {idx} is not in the tag file"),
+            Error::UnknownFile(idx) => write!(f, "file {idx} is not in the tag file"),
+            Error::UnknownBranch(idx) => write!(f, "branch {idx} is not in the tag file"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Format(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<format::Error> for Error {
+    fn from(err: format::Error) -> Self {
+        Error::Format(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Format(err.into())
+    }
+}
+
+fn timestamp(time: i64) -> Result<DateTime<Utc>, Error> {
+    DateTime::from_timestamp(time, 0).ok_or(Error::InvalidTimestamp(time))
+}
+
+/// Decodes the log file into [`RawSession`]s.
+pub struct RawReader<R> {
+    inner: R,
+    header: Header,
+    /// The last session that was started, which lasts until the next record.
+    pending: Option<RawSession>,
+    done: bool,
+}
+
+impl<R: Read + Seek> RawReader<R> {
+    /// Reads and validates the header at the start of `inner`.
+    pub fn new(mut inner: R) -> Result<Self, Error> {
+        let header = Header::read(&mut inner)?;
+        Ok(Self {
+            inner,
+            header,
+            pending: None,
+            done: false,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    fn next_session(&mut self) -> Result<Option<RawSession>, Error> {
+        loop {
+            let Some(record) = Record::read(&mut self.inner, &self.header)? else {
+                return Ok(self.pending.take());
+            };
+
+            match record {
+                Record::End { time } => {
+                    let end = timestamp(time)?;
+                    if let Some(session) = self.pending.take() {
+                        return Ok(Some(RawSession { end, ..session }));
+                    }
+                }
+                Record::Start {
+                    language,
+                    project,
+                    file,
+                    branch,
+                    time,
+                } => {
+                    let start = timestamp(time)?;
+                    let next = RawSession {
+                        language,
+                        project,
+                        file,
+                        branch,
+                        start,
+                        end: start,
+                    };
+
+                    if let Some(session) = self.pending.replace(next) {
+                        return Ok(Some(RawSession {
+                            end: start,
+                            ..session
+                        }));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read + Seek> Iterator for RawReader<R> {
+    type Item = Result<RawSession, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let result = self.next_session().transpose();
+        if !matches!(result, Some(Ok(_))) {
+            self.done = true;
+        }
+        result
+    }
+}
+
+/// Decodes the log file into [`Session`]s, resolving language, project, file
+/// and branch indices through their tag files.
+pub struct Reader<'a, R> {
+    raw: RawReader<R>,
+    languages: &'a Tags,
+    /// Display names of the projects, see [`Projects::names`].
+    projects: Vec<String>,
+    files: &'a Tags,
+    branches: &'a Tags,
+}
+
+impl<'a> Reader<'a, BufReader<File>> {
+    pub fn open(
+        path: impl AsRef<Path>,
+        languages: &'a Tags,
+        projects: &'a Projects,
+        files: &'a Tags,
+        branches: &'a Tags,
+    ) -> Result<Self, Error> {
+        Self::new(
+            BufReader::new(File::open(path)?),
+            languages,
+            projects,
+            files,
+            branches,
+        )
+    }
+}
+
+impl<'a, R: Read + Seek> Reader<'a, R> {
+    pub fn new(
+        inner: R,
+        languages: &'a Tags,
+        projects: &'a Projects,
+        files: &'a Tags,
+        branches: &'a Tags,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            raw: RawReader::new(inner)?,
+            languages,
+            projects: projects.names(),
+            files,
+            branches,
+        })
+    }
+}
+
+impl<R: Read + Seek> Iterator for Reader<'_, R> {
+    type Item = Result<Session, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let raw = match self.raw.next()? {
+            Ok(raw) => raw,
+            Err(err) => return Some(Err(err)),
+        };
+
+        let Some(language) = self.languages.name(raw.language) else {
+            return Some(Err(Error::UnknownLanguage(raw.language)));
+        };
+        let Some(project) = self.projects.get(raw.project).cloned() else {
+            return Some(Err(Error::UnknownProject(raw.project)));
+        };
+        let file = match raw.file {
+            Some(file) => match self.files.name(file) {
+                Some(name) => Some(name),
+                None => return Some(Err(Error::UnknownFile(file))),
+            },
+            None => None,
+        };
+        let branch = match raw.branch {
+            Some(branch) => match self.branches.name(branch) {
+                Some(name) => Some(name),
+                None => return Some(Err(Error::UnknownBranch(branch))),
+            },
+            None => None,
+        };
+
+        Some(Ok(Session {
+            language,
+            project,
+            file,
+            branch,
+            start: raw.start,
+            end: raw.end,
+        }))
+    }
+}
+
+/// Reads every session in the log, resolving names through the tag files.
+/// Nothing is created, so before anything was recorded there are no sessions.
+pub async fn read_sessions() -> Result<Vec<Session>, Error> {
+    let languages = Tags::load("languages").await?;
+    let projects = Projects::load().await?;
+    let files = Tags::load("files").await?;
+    let branches = Tags::load("branches").await?;
+
+    unblock(move || {
+        let reader = match Reader::open(log_path(), &languages, &projects, &files, &branches) {
+            Err(Error::Format(format::Error::Io(err))) if err.kind() == ErrorKind::NotFound => {
+                return Ok(Vec::new())
+            }
+            reader => reader?,
+        };
+        reader.collect()
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn log(records: &[Record]) -> Vec<u8> {
+        let header = Header::CURRENT;
+        let mut bytes = header.encode().to_vec();
+        for record in records {
+            bytes.extend(record.encode(header.byte_order));
+        }
+        bytes
+    }
+
+    fn start(language: usize, time: i64) -> Record {
+        Record::Start {
+            language,
+            project: 0,
+            file: None,
+            branch: None,
+            time,
+        }
+    }
+
+    fn session(language: usize, start: i64, end: i64) -> RawSession {
+        RawSession {
+            language,
+            project: 0,
+            file: None,
+            branch: None,
+            start: timestamp(start).unwrap(),
+            end: timestamp(end).unwrap(),
+        }
+    }
+
+    fn read(bytes: Vec<u8>) -> Vec<Result<RawSession, Error>> {
+        RawReader::new(Cursor::new(bytes)).unwrap().collect()
+    }
+
+    #[test]
+    fn start_ends_the_previous_session() {
+        let bytes = log(&[start(0, 100), start(1, 150), Record::End { time: 200 }]);
+
+        let sessions = read(bytes).into_iter().collect::<Result<Vec<_>, _>>();
+        assert_eq!(
+            sessions.unwrap(),
+            vec![session(0, 100, 150), session(1, 150, 200)]
+        );
+    }
+
+    #[test]
+    fn end_without_start_is_skipped() {
+        let bytes = log(&[
+            Record::End { time: 50 },
+            start(0, 100),
+            Record::End { time: 160 },
+            Record::End { time: 170 },
+        ]);
+
+        let sessions = read(bytes).into_iter().collect::<Result<Vec<_>, _>>();
+        assert_eq!(sessions.unwrap(), vec![session(0, 100, 160)]);
+    }
+
+    #[test]
+    fn log_cut_off_mid_record() {
+        let mut bytes = log(&[start(0, 100), Record::End { time: 160 }, start(1, 200)]);
+        bytes.pop();
+
+        let mut sessions = read(bytes).into_iter();
+        assert_eq!(sessions.next().unwrap().unwrap(), session(0, 100, 160));
+        assert!(matches!(
+            sessions.next(),
+            Some(Err(Error::Format(format::Error::Truncated)))
+        ));
+        assert!(sessions.next().is_none());
+    }
+}
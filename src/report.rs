@@ -0,0 +1,222 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Write},
+};
+
+use chrono::{DateTime, Datelike, Days, Duration, Local, NaiveDate, Utc};
+
+use crate::{config::LanguagesConfig, reader::Session};
+
+/// The period of time a report covers.
+#[derive(Debug, Clone, Copy)]
+pub enum Range {
+    Today,
+    Week,
+    Month,
+    Custom {
+        since: Option<NaiveDate>,
+        until: Option<NaiveDate>,
+    },
+}
+
+fn start_of_day(date: NaiveDate) -> DateTime<Utc> {
+    date.and_hms_opt(0, 0, 0)
+        .unwrap()
+        .and_local_timezone(Local)
+        .earliest()
+        .expect("Local midnight does not exist")
+        .to_utc()
+}
+
+impl Range {
+    /// Resolves the range into `[start, end)` relative to `now`. The `until`
+    /// date of a custom range is inclusive.
+    pub fn bounds(self, now: DateTime<Local>) -> (DateTime<Utc>, DateTime<Utc>) {
+        let today = now.date_naive();
+        match self {
+            Range::Today => (start_of_day(today), now.to_utc()),
+            Range::Week => (
+                start_of_day(today - Days::new(today.weekday().num_days_from_monday().into())),
+                now.to_utc(),
+            ),
+            Range::Month => (start_of_day(today.with_day(1).unwrap()), now.to_utc()),
+            Range::Custom { since, until } => (
+                since.map(start_of_day).unwrap_or(DateTime::<Utc>::MIN_UTC),
+                until
+                    .map(|until| start_of_day(until + Days::new(1)))
+                    .unwrap_or(now.to_utc()),
+            ),
+        }
+    }
+}
+
+/// How many files are listed for each project when printing a report.
+const FILES_PER_PROJECT: usize = 10;
+
+/// Total time per language, per project, and per language, file and branch in
+/// each project.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub total: Duration,
+    pub languages: HashMap<String, Duration>,
+    /// Total time of each language group, see [`Report::group`].
+    pub groups: HashMap<String, Duration>,
+    pub projects: HashMap<String, Duration>,
+    pub project_languages: HashMap<String, HashMap<String, Duration>>,
+    pub project_files: HashMap<String, HashMap<String, Duration>>,
+    pub project_branches: HashMap<String, HashMap<String, Duration>>,
+}
+
+impl Report {
+    /// Sums up `sessions`, only counting the parts of each session that fall
+    /// within `[start, end)`.
+    pub fn new(
+        sessions: impl IntoIterator<Item = Session>,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Self {
+        let mut report = Report::default();
+
+        for session in sessions {
+            let duration = session.end.min(end) - session.start.max(start);
+            if duration <= Duration::zero() {
+                continue;
+            }
+
+            report.total += duration;
+            *report
+                .languages
+                .entry(session.language.clone())
+                .or_default() += duration;
+            *report.projects.entry(session.project.clone()).or_default() += duration;
+            if let Some(file) = session.file {
+                *report
+                    .project_files
+                    .entry(session.project.clone())
+                    .or_default()
+                    .entry(file)
+                    .or_default() += duration;
+            }
+            if let Some(branch) = session.branch {
+                *report
+                    .project_branches
+                    .entry(session.project.clone())
+                    .or_default()
+                    .entry(branch)
+                    .or_default() += duration;
+            }
+            *report
+                .project_languages
+                .entry(session.project)
+                .or_default()
+                .entry(session.language)
+                .or_default() += duration;
+        }
+
+        report
+    }
+
+    /// Sums up the time spent in the languages of each group, with the
+    /// languages in `self.languages` having been resolved through `config`.
+    pub fn group(&mut self, config: &LanguagesConfig) {
+        self.groups = config
+            .groups
+            .iter()
+            .map(|(group, members)| {
+                let members = members
+                    .iter()
+                    .map(|member| config.resolve(member))
+                    .collect::<HashSet<_>>();
+                let duration = members
+                    .into_iter()
+                    .filter_map(|member| self.languages.get(member))
+                    .sum();
+                (group.clone(), duration)
+            })
+            .collect();
+    }
+
+    pub fn print(&self, mut out: impl Write) -> io::Result<()> {
+        writeln!(out, "Total: {}", format_duration(self.total))?;
+
+        writeln!(out, "\nLanguages")?;
+        print_table(&mut out, &self.languages, "  ")?;
+
+        if !self.groups.is_empty() {
+            writeln!(out, "\nLanguage groups")?;
+            print_table(&mut out, &self.groups, "  ")?;
+        }
+
+        writeln!(out, "\nProjects")?;
+        print_table(&mut out, &self.projects, "  ")?;
+
+        writeln!(out, "\nLanguages by project")?;
+        for (project, _) in sorted(&self.projects) {
+            writeln!(out, "  {project}")?;
+            print_table(&mut out, &self.project_languages[project], "    ")?;
+        }
+
+        if !self.project_files.is_empty() {
+            writeln!(out, "\nFiles by project")?;
+            for (project, _) in sorted(&self.projects) {
+                let Some(files) = self.project_files.get(project) else {
+                    continue;
+                };
+                writeln!(out, "  {project}")?;
+                let files = sorted(files)
+                    .into_iter()
+                    .take(FILES_PER_PROJECT)
+                    .map(|(file, duration)| (file.clone(), *duration))
+                    .collect();
+                print_table(&mut out, &files, "    ")?;
+            }
+        }
+
+        if !self.project_branches.is_empty() {
+            writeln!(out, "\nBranches by project")?;
+            for (project, _) in sorted(&self.projects) {
+                let Some(branches) = self.project_branches.get(project) else {
+                    continue;
+                };
+                writeln!(out, "  {project}")?;
+                print_table(&mut out, branches, "    ")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Orders entries from the most to the least time spent.
+pub fn sorted(durations: &HashMap<String, Duration>) -> Vec<(&String, &Duration)> {
+    let mut entries = durations.iter().collect::<Vec<_>>();
+    entries.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+    entries
+}
+
+fn print_table(
+    out: &mut impl Write,
+    durations: &HashMap<String, Duration>,
+    indent: &str,
+) -> io::Result<()> {
+    let width = durations.keys().map(|name| name.len()).max().unwrap_or(0);
+    for (name, duration) in sorted(durations) {
+        writeln!(out, "{indent}{name:width$}  {}", format_duration(*duration))?;
+    }
+    Ok(())
+}
+
+/// Formats a duration as hours, minutes and seconds, leaving out the larger
+/// units when they are zero (e.g. `2h 05m`, `12m 30s`, `45s`).
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.num_seconds();
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+
+    if hours > 0 {
+        format!("{hours}h {minutes:02}m")
+    } else if minutes > 0 {
+        format!("{minutes}m {seconds:02}s")
+    } else {
+        format!("{seconds}s")
+    }
+}
@@ -0,0 +1,200 @@
+//! # D-Bus interface for: `org.codestatistics.Daemon`
+//!
+//! Published on the session bus so desktop widgets and status bars can show
+//! what is being recorded and query totals without parsing the log.
+use std::{rc::Rc, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use tracing::{debug, warn};
+use zbus::{
+    connection, fdo, interface,
+    object_server::{InterfaceRef, SignalEmitter},
+    Connection,
+};
+
+use crate::{
+    log::Status,
+    projects::Projects,
+    reader::{read_sessions, Session},
+    report::{sorted, Report},
+    tags::Tags,
+    Receiver,
+};
+
+pub const NAME: &str = "org.codestatistics.Daemon";
+pub const PATH: &str = "/org/codestatistics/Daemon";
+
+/// What the daemon is currently recording.
+#[derive(Debug, Clone, Default)]
+pub struct CurrentStatus {
+    pub active: bool,
+    pub language: String,
+    pub project: String,
+    pub branch: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+}
+
+pub struct Daemon {
+    status: Arc<Mutex<CurrentStatus>>,
+}
+
+async fn all_sessions() -> fdo::Result<Vec<Session>> {
+    read_sessions()
+        .await
+        .map_err(|err| fdo::Error::Failed(err.to_string()))
+}
+
+#[interface(name = "org.codestatistics.Daemon")]
+impl Daemon {
+    /// Whether a session is currently being recorded.
+    #[zbus(property)]
+    fn active(&self) -> bool {
+        self.status.lock().active
+    }
+
+    /// Language of the current session, empty when not active.
+    #[zbus(property)]
+    fn language(&self) -> String {
+        self.status.lock().language.clone()
+    }
+
+    /// Project of the current session, empty when not active.
+    #[zbus(property)]
+    fn project(&self) -> String {
+        self.status.lock().project.clone()
+    }
+
+    /// Git branch of the current session, empty when not active or unknown.
+    #[zbus(property)]
+    fn branch(&self) -> String {
+        self.status.lock().branch.clone().unwrap_or_default()
+    }
+
+    /// Unix timestamp the current session started at, 0 when not active.
+    #[zbus(property)]
+    fn since(&self) -> i64 {
+        self.status
+            .lock()
+            .since
+            .map_or(0, |since| since.timestamp())
+    }
+
+    /// Seconds spent coding between the unix timestamps `start` and `end`.
+    async fn total_for_range(&self, start: i64, end: i64) -> fdo::Result<u64> {
+        let (Some(start), Some(end)) = (
+            DateTime::from_timestamp(start, 0),
+            DateTime::from_timestamp(end, 0),
+        ) else {
+            return Err(fdo::Error::InvalidArgs("Invalid timestamp".to_owned()));
+        };
+
+        let report = Report::new(all_sessions().await?, start, end);
+        Ok(report.total.num_seconds().max(0) as u64)
+    }
+
+    /// The `n` projects with the most time recorded, with their totals in
+    /// seconds.
+    async fn top_projects(&self, n: u32) -> fdo::Result<Vec<(String, u64)>> {
+        let report = Report::new(
+            all_sessions().await?,
+            DateTime::<Utc>::MIN_UTC,
+            DateTime::<Utc>::MAX_UTC,
+        );
+
+        Ok(sorted(&report.projects)
+            .into_iter()
+            .take(n as usize)
+            .map(|(project, duration)| (project.clone(), duration.num_seconds().max(0) as u64))
+            .collect())
+    }
+
+    /// Emitted whenever a session starts or ends.
+    #[zbus(signal)]
+    async fn status_changed(
+        emitter: &SignalEmitter<'_>,
+        active: bool,
+        language: &str,
+        project: &str,
+    ) -> zbus::Result<()>;
+}
+
+async fn notify(
+    emitter: &SignalEmitter<'_>,
+    iface: &Daemon,
+    status: &CurrentStatus,
+) -> zbus::Result<()> {
+    iface.active_changed(emitter).await?;
+    iface.language_changed(emitter).await?;
+    iface.project_changed(emitter).await?;
+    iface.branch_changed(emitter).await?;
+    iface.since_changed(emitter).await?;
+
+    Daemon::status_changed(emitter, status.active, &status.language, &status.project).await
+}
+
+async fn publish(
+    status: Arc<Mutex<CurrentStatus>>,
+) -> zbus::Result<(Connection, InterfaceRef<Daemon>)> {
+    let connection = connection::Builder::session()?
+        .name(NAME)?
+        .serve_at(PATH, Daemon { status })?
+        .build()
+        .await?;
+
+    let iface = connection
+        .object_server()
+        .interface::<_, Daemon>(PATH)
+        .await?;
+
+    Ok((connection, iface))
+}
+
+/// Keeps `status` up to date with the updates sent by the log task, and
+/// publishes it on the session bus when one is available.
+pub async fn serve(
+    updates: Receiver<Status, 5>,
+    status: Arc<Mutex<CurrentStatus>>,
+    languages: Rc<Tags>,
+    projects: Rc<Projects>,
+    branches: Rc<Tags>,
+) {
+    let published = match publish(status.clone()).await {
+        Ok(published) => Some(published),
+        Err(err) => {
+            warn!(%err, "Failed to publish on the session bus");
+            None
+        }
+    };
+
+    while let Some(update_status) = updates.recv().await {
+        debug!(?update_status);
+
+        let new_status = match update_status {
+            Status::Active {
+                time,
+                language,
+                project,
+                branch,
+                ..
+            } => CurrentStatus {
+                active: true,
+                language: languages.name(language).unwrap_or_default(),
+                project: projects.name(project).unwrap_or_default(),
+                branch: branch.and_then(|branch| branches.name(branch)),
+                since: Some(time),
+            },
+            Status::Dormant { .. } => CurrentStatus::default(),
+        };
+
+        *status.lock() = new_status.clone();
+
+        let Some((_, iface)) = &published else {
+            continue;
+        };
+
+        if let Err(err) = notify(iface.signal_emitter(), &*iface.get().await, &new_status).await {
+            warn!(%err, "Failed to send status update");
+        }
+    }
+}
@@ -0,0 +1,64 @@
+//! # D-Bus interface proxy for: `org.freedesktop.login1.Session`
+//!
+//! This code was generated by `zbus-xmlgen` `5.1.0` from D-Bus introspection data.
+//! Source: `Interface '/org/freedesktop/login1/session/auto' from service 'org.freedesktop.login1' on system bus`.
+//!
+//! Only the members needed to follow the lock and idle state of the session
+//! were kept.
+//!
+//! More information can be found in the [Writing a client proxy] section of the zbus
+//! documentation.
+//!
+//! This type implements the [D-Bus standard interfaces], (`org.freedesktop.DBus.*`) for which the
+//! following zbus API can be used:
+//!
+//! * [`zbus::fdo::PeerProxy`]
+//! * [`zbus::fdo::IntrospectableProxy`]
+//! * [`zbus::fdo::PropertiesProxy`]
+//!
+//! Consequently `zbus-xmlgen` did not generate code for the above interfaces.
+//!
+//! [Writing a client proxy]: https://dbus2.github.io/zbus/client.html
+//! [D-Bus standard interfaces]: https://dbus.freedesktop.org/doc/dbus-specification.html#standard-interfaces,
+use zbus::proxy;
+#[proxy(
+    interface = "org.freedesktop.login1.Session",
+    default_service = "org.freedesktop.login1"
+)]
+pub trait Session {
+    /// Lock signal
+    #[zbus(signal)]
+    fn lock(&self) -> zbus::Result<()>;
+
+    /// Unlock signal
+    #[zbus(signal)]
+    fn unlock(&self) -> zbus::Result<()>;
+
+    /// Active property
+    #[zbus(property)]
+    fn active(&self) -> zbus::Result<bool>;
+
+    /// Id property
+    #[zbus(property)]
+    fn id(&self) -> zbus::Result<String>;
+
+    /// IdleHint property
+    #[zbus(property)]
+    fn idle_hint(&self) -> zbus::Result<bool>;
+
+    /// IdleSinceHint property
+    #[zbus(property)]
+    fn idle_since_hint(&self) -> zbus::Result<u64>;
+
+    /// IdleSinceHintMonotonic property
+    #[zbus(property)]
+    fn idle_since_hint_monotonic(&self) -> zbus::Result<u64>;
+
+    /// LockedHint property
+    #[zbus(property)]
+    fn locked_hint(&self) -> zbus::Result<bool>;
+
+    /// State property
+    #[zbus(property)]
+    fn state(&self) -> zbus::Result<String>;
+}
@@ -1,23 +1,54 @@
-use std::cell::RefCell;
+use std::{
+    cell::RefCell,
+    fs,
+    io::{self, ErrorKind},
+    path::PathBuf,
+};
 
-use dirs::data_dir;
 use smol::{
-    fs::{File, OpenOptions},
+    fs::{self as async_fs, File, OpenOptions},
     io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
     lock::Mutex,
 };
 use tracing::debug;
 
+use crate::paths::data_dir;
+
+/// One tag per line. Only the line break is dropped when reading, since a
+/// tag can start with whitespace, like a project with an empty name followed
+/// by [`crate::projects::SEPARATOR`].
 pub struct Tags {
     tags: RefCell<Vec<String>>,
-    file: Mutex<File>,
+    /// `None` when the tags were only loaded for reading.
+    file: Option<Mutex<File>>,
+}
+
+/// Path of the tag file called `name`.
+pub fn tags_path(name: &str) -> PathBuf {
+    data_dir().join(name)
+}
+
+/// Replaces the contents of the tag file called `name`. The daemon keeps the
+/// tags in memory, so this must only be used while it is stopped.
+pub fn write_tags(name: &str, tags: &[String]) -> io::Result<()> {
+    let path = tags_path(name);
+    let tmp_path = path.with_extension("tmp");
+
+    let mut contents = String::new();
+    for tag in tags {
+        contents.push_str(tag);
+        contents.push('\n');
+    }
+
+    fs::write(&tmp_path, contents)?;
+    fs::rename(&tmp_path, &path)
 }
 
 impl Tags {
-    pub async fn new(name: &str) -> Self {
-        let mut file_path = data_dir().expect("Could not find data directory");
-        file_path.push("code-statistics");
-        file_path.push(name);
+    /// Opens the tag file called `name` for adding tags, creating it if it
+    /// doesn't exist yet.
+    pub async fn new(name: &str) -> io::Result<Self> {
+        let file_path = tags_path(name);
 
         let mut file = OpenOptions::new()
             .create(true)
@@ -25,55 +56,87 @@ impl Tags {
             .write(true)
             .append(true)
             .open(file_path)
-            .await
-            .expect("Failed to open tag path");
+            .await?;
 
         let mut reader = BufReader::new(&mut file);
         let mut tags = Vec::new();
 
         let mut line = String::new();
-        while reader
-            .read_line(&mut line)
-            .await
-            .expect("Failed to read from tag file")
-            != 0
-        {
-            tags.push(std::mem::take(&mut line).trim().to_string());
+        while reader.read_line(&mut line).await? != 0 {
+            tags.push(line.trim_end_matches(['\n', '\r']).to_owned());
+            line.clear();
         }
 
         debug!(?tags);
 
-        Self {
+        Ok(Self {
             tags: RefCell::new(tags),
-            file: Mutex::new(file),
-        }
+            file: Some(Mutex::new(file)),
+        })
     }
 
-    pub async fn get(&self, language: &str) -> usize {
-        let pos = {
-            let tags = self.tags.borrow();
-            tags.iter()
-                .position(|known_language| known_language == language)
+    /// Reads the tag file called `name` without creating it, for looking tags
+    /// up. A missing file has no tags yet.
+    pub async fn load(name: &str) -> io::Result<Self> {
+        let tags = match async_fs::read_to_string(tags_path(name)).await {
+            Ok(contents) => contents.lines().map(str::to_owned).collect(),
+            Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
         };
 
+        Ok(Self {
+            tags: RefCell::new(tags),
+            file: None,
+        })
+    }
+
+    /// Looks up the tag stored at `idx` without adding anything to the file.
+    pub fn name(&self, idx: usize) -> Option<String> {
+        self.tags.borrow().get(idx).cloned()
+    }
+
+    /// Every tag, in the order they were added.
+    pub fn all(&self) -> Vec<String> {
+        self.tags.borrow().clone()
+    }
+
+    /// Finds the first tag `predicate` returns true for.
+    pub fn position(&self, predicate: impl FnMut(&String) -> bool) -> Option<usize> {
+        self.tags.borrow().iter().position(predicate)
+    }
+
+    /// Appends `tag` to the file without checking whether it is already
+    /// there, returning its index.
+    pub async fn push(&self, tag: &str) -> usize {
+        let mut file = self
+            .file
+            .as_ref()
+            .expect("Tags loaded for reading can't be added to")
+            .lock()
+            .await;
+        file.write_all(tag.as_bytes())
+            .await
+            .expect("Failed to write to tag file");
+        file.write_all("\n".as_bytes())
+            .await
+            .expect("Failed to write to tag file");
+
+        let mut tags = self.tags.borrow_mut();
+
+        tags.push(tag.to_owned());
+
+        tags.len() - 1
+    }
+
+    pub async fn get(&self, language: &str) -> usize {
+        let pos = self.position(|known_language| known_language == language);
+
         debug!(?pos);
 
         if let Some(pos) = pos {
             pos
         } else {
-            let mut file = self.file.lock().await;
-            file.write_all(language.as_bytes())
-                .await
-                .expect("Failed to write to tag file");
-            file.write_all("\n".as_bytes())
-                .await
-                .expect("Failed to write to tag file");
-
-            let mut tags = self.tags.borrow_mut();
-
-            tags.push(language.to_owned());
-
-            tags.len() - 1
+            self.push(language).await
         }
     }
 }